@@ -1,16 +1,27 @@
 use pyo3::prelude::*;
 
+mod cache;
 mod filedb;
 mod fsdb;
 mod nil;
+mod overlay;
 mod pydb;
 mod ram;
+mod sharded;
+mod sqlitedb;
+mod tiered;
 
 pub fn populate_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(nil::hash, m)?)?;
+    m.add_class::<cache::Cache>()?;
     m.add_class::<fsdb::FsDB>()?;
     m.add_class::<filedb::FileDB>()?;
+    m.add_class::<filedb::Recovery>()?;
+    m.add_class::<overlay::Overlay>()?;
     m.add_class::<pydb::PyDB>()?;
     m.add_class::<ram::Ram>()?;
+    m.add_class::<sharded::Sharded>()?;
+    m.add_class::<sqlitedb::SqliteDB>()?;
+    m.add_class::<tiered::Tiered>()?;
     Ok(())
 }
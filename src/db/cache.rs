@@ -0,0 +1,178 @@
+use pyo3::{
+    pyclass, pymethods,
+    types::{PyBytes, PyIterator},
+    Bound, PyAny, PyResult, Python,
+};
+
+use crate::{
+    deserialize::deserialize,
+    gc::{collect, extract_roots, Report},
+    mapping::{content_equals, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put},
+    nohash::NoHashBuilder,
+    serialize::{serialize, FunctionMode},
+};
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+// An in-memory store with a budget for the total size of its blobs, which evicts blobs by the
+// CLOCK algorithm when the budget is exceeded. Every blob has a reference bit that is set when it
+// is read, and the blobs are kept in a ring in order of insertion. To make room, the hand of the
+// clock passes over the ring, evicting the first blob whose bit is clear and clearing the bits it
+// passes over, giving those blobs a second chance. A blob larger than the entire budget is not
+// stored at all.
+//
+// The cache is lossy: blobs may be evicted, or not stored at all, while an object is hashed, so
+// that a hash returned by `hash` need not be resolvable by `unhash`. It is therefore only safe as
+// a tier of a write-through `Tiered` store, above a store that holds every blob, and not as a store
+// in its own right.
+#[pyclass(name = "Cache")]
+pub struct Cache {
+    blobs: HashMap<Key, (Vec<u8>, AtomicBool), NoHashBuilder>,
+    ring: VecDeque<Key>,
+    #[pyo3(get)]
+    capacity: usize,
+    #[pyo3(get)]
+    nbytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    #[pyo3(get)]
+    evictions: u64,
+    functions: FunctionMode,
+}
+
+impl Cache {
+    // Evict blobs until there is room for a blob of the given size.
+    fn make_room(&mut self, len: usize) {
+        while self.nbytes + len > self.capacity {
+            let Some(h) = self.ring.pop_front() else {
+                break;
+            };
+            let (b, referenced) = &self.blobs[&h];
+            if referenced.swap(false, Ordering::Relaxed) {
+                self.ring.push_back(h);
+            } else {
+                self.nbytes -= b.len();
+                self.evictions += 1;
+                self.blobs.remove(&h);
+            }
+        }
+    }
+}
+
+impl Put for Cache {
+    fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
+        let b = b.as_ref();
+        if let Some((stored, referenced)) = self.blobs.get(&h) {
+            if !content_equals(stored.as_slice(), b)? {
+                return Err(MappingError::Collision(h));
+            }
+            referenced.store(true, Ordering::Relaxed);
+        } else if b.len() <= self.capacity {
+            self.make_room(b.len());
+            self.nbytes += b.len();
+            self.blobs.insert(h, (b.to_vec(), AtomicBool::new(false)));
+            self.ring.push_back(h);
+        }
+        Ok(())
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        match self.blobs.entry(h) {
+            Entry::Occupied(e) => {
+                self.nbytes -= e.remove().0.len();
+                self.ring.retain(|k| *k != h);
+                Ok(())
+            }
+            Entry::Vacant(_) => Err(MappingError::NotFound(h)),
+        }
+    }
+}
+
+impl Get for Cache {
+    fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
+        match self.blobs.get(&h) {
+            Some((b, referenced)) => {
+                referenced.store(true, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(b.deref())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Err(MappingError::NotFound(h))
+            }
+        }
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Ok(self.blobs.contains_key(&h))
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        Ok(self.blobs.keys().copied().collect())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self.blobs.len())
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        self.blobs
+            .get(&h)
+            .map_or_else(|| Err(MappingError::NotFound(h)), |(b, _)| Ok(b.len()))
+    }
+}
+
+#[pymethods]
+impl Cache {
+    #[new]
+    #[pyo3(signature = (capacity, *, functions = FunctionMode::Name))]
+    fn py_new(capacity: usize, functions: FunctionMode) -> Self {
+        Self {
+            blobs: HashMap::default(),
+            ring: VecDeque::new(),
+            capacity,
+            nbytes: 0,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: 0,
+            functions,
+        }
+    }
+    #[getter]
+    fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    #[getter]
+    fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, self, functions.unwrap_or(self.functions))
+    }
+    fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
+        deserialize(obj, self)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        Ok(collect(self, &extract_roots(roots)?, dry_run)?)
+    }
+}
@@ -1,121 +1,606 @@
-use pyo3::{pyclass, pymethods, types::PyBytes, Bound, PyAny, PyResult};
+use pyo3::{
+    pyclass, pymethods,
+    types::{PyBytes, PyIterator},
+    Bound, PyAny, PyResult, Python,
+};
 
 use crate::{
     deserialize::deserialize,
-    mapping::{Get, Key, MappingError, MappingResult, Put, NBYTES},
+    gc::{collect, extract_roots, Report},
+    mapping::{
+        content_equals, digest, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put,
+        NBYTES,
+    },
     nohash::NoHashBuilder,
-    serialize::serialize,
+    serialize::{serialize, FunctionMode},
 };
 
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::HashMap,
     fs::File,
     io::{Read, Seek, Write},
     ops::Deref,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
 };
 
+use memmap2::Mmap;
+
+// The first bytes of a database file, which include the version of its format.
+const MAGIC: &[u8; 8] = b"STASHDB1";
+
+// A record consists of a header with the hash and the length of the data, the data itself, and a
+// checksum over the header and data that serves to detect records that were not fully written.
+const HEADER: usize = NBYTES + 8;
+const CHECKSUM: usize = NBYTES;
+
+// The length field of a record that marks the deletion of the preceding records with its hash.
+const TOMBSTONE: u64 = u64::MAX;
+
+// The first bytes of an index file, which include the version of its format.
+const INDEX_MAGIC: &[u8; 8] = b"STASHIX1";
+
+// The number of bytes at the end of the indexed part of the data file that are digested to verify
+// that the index belongs to the data file.
+const INDEX_TAIL: u64 = 4096;
+
+// The position and length of the data of every record, by hash.
+type Offsets = HashMap<Key, (u64, usize), NoHashBuilder>;
+
+// The database is shared safely between processes by taking an advisory lock on the data file:
+// an exclusive lock to append records and a shared lock to scan them. A lookup that misses scans
+// the records that other processes appended since the file was last scanned.
 #[pyclass(name = "FileDB")]
 pub struct FileDB {
+    records: Mutex<Records>,
+    recovery: Recovery,
+    functions: FunctionMode,
+}
+
+// The data file and the offsets of the records that were read from it, along with the position up
+// to which it was read and the length of the part that is covered by the index file. The file is
+// mapped into memory on the first read, and mapped anew when a read goes past the mapping.
+struct Records {
+    path: PathBuf,
     file: File,
-    offsets: HashMap<Key, (u64, usize), NoHashBuilder>,
+    map: Option<Arc<Mmap>>,
+    offsets: Offsets,
+    end: u64,
+    indexed: u64,
 }
 
-impl FileDB {
-    fn new(path: PathBuf) -> std::io::Result<Self> {
-        let mut offsets = HashMap::default();
+// The data of a record in the mapped file, which keeps the mapping alive when the file is mapped
+// anew.
+struct Slice {
+    map: Arc<Mmap>,
+    pos: usize,
+    len: usize,
+}
+
+impl Deref for Slice {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.map[self.pos..self.pos + self.len]
+    }
+}
+
+// The outcome of opening a database file: the number of records that were scanned because they
+// were not covered by the index, and the number of bytes that were truncated from the end of the
+// file because they did not form complete records with valid checksums. Bytes that are truncated
+// later on, when the file is locked for writing, are added to the count.
+#[pyclass(frozen, get_all, name = "RecoveryReport")]
+#[derive(Clone, Default)]
+pub struct Recovery {
+    scanned: usize,
+    truncated: u64,
+}
+
+#[pymethods]
+impl Recovery {
+    fn __repr__(&self) -> String {
+        format!(
+            "RecoveryReport(scanned={}, truncated={})",
+            self.scanned, self.truncated
+        )
+    }
+}
+
+// Form a record with the given hash and data, or a tombstone if the data is None.
+fn new_record(h: &Key, data: Option<&[u8]>) -> Vec<u8> {
+    let len = data.map_or(TOMBSTONE, |data| data.len() as u64);
+    let data = data.unwrap_or_default();
+    let mut record = Vec::with_capacity(HEADER + data.len() + CHECKSUM);
+    record.extend_from_slice(h);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(data);
+    let checksum = digest(&record);
+    record.extend_from_slice(&checksum);
+    record
+}
+
+// Form the path of a file next to the database file by appending a suffix to its name.
+fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+// Whether the file is still the one at the path, rather than one that was replaced by the
+// compaction of another process. Only unix exposes the identity of a file, so on other platforms
+// the replacement goes unnoticed, and a database must not be compacted while other processes have
+// it open.
+fn is_current(file: &File, path: &Path) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let (a, b) = (file.metadata()?, std::fs::metadata(path)?);
+        Ok(a.dev() == b.dev() && a.ino() == b.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (file, path);
+        Ok(true)
+    }
+}
+
+// Digest the last bytes of the first `len` bytes of the data file.
+fn tail_digest(mut file: &File, len: u64) -> std::io::Result<Key> {
+    let n = len.min(INDEX_TAIL);
+    file.seek(std::io::SeekFrom::Start(len - n))?;
+    let mut v = vec![0; n as usize];
+    file.read_exact(&mut v)?;
+    Ok(digest(&v))
+}
+
+// Read the records from position `pos` onward, and update the offsets accordingly. Scanning stops
+// at the first record that is incomplete or fails its checksum. Returns the number of records read
+// and the position at which the valid records end.
+fn scan_records(
+    file: &File,
+    offsets: &mut Offsets,
+    mut pos: u64,
+    filesize: u64,
+) -> std::io::Result<(usize, u64)> {
+    let mut file = std::io::BufReader::new(file);
+    let mut record = Vec::new();
+    let mut nrecords = 0;
+    file.seek(std::io::SeekFrom::Start(pos))?;
+    while filesize - pos >= (HEADER + CHECKSUM) as u64 {
+        record.resize(HEADER, 0);
+        file.read_exact(&mut record)?;
+        let h: Key = record[..NBYTES].try_into().unwrap();
+        let len = u64::from_le_bytes(record[NBYTES..].try_into().unwrap());
+        let datalen = if len == TOMBSTONE { 0 } else { len };
+        if datalen > filesize - pos - (HEADER + CHECKSUM) as u64 {
+            break;
+        }
+        record.resize(HEADER + datalen as usize + CHECKSUM, 0);
+        file.read_exact(&mut record[HEADER..])?;
+        let (content, checksum) = record.split_at(HEADER + datalen as usize);
+        if digest(content) != checksum {
+            break;
+        }
+        if len == TOMBSTONE {
+            offsets.remove(&h);
+        } else {
+            offsets.insert(h, (pos + HEADER as u64, len as usize));
+        }
+        pos += record.len() as u64;
+        nrecords += 1;
+    }
+    Ok((nrecords, pos))
+}
+
+// Read the index file of a database, and return the offsets along with the length of the part of
+// the data file that they cover. The index file consists of the magic bytes, the indexed length,
+// the digest of the tail of the indexed part, the hash, position and length of every record, and
+// finally a checksum over all of the preceding. Returns None if the index file is missing,
+// corrupted or does not match the data file.
+fn read_index(path: &Path, file: &File, filesize: u64) -> Option<(Offsets, u64)> {
+    let b = std::fs::read(sidecar(path, ".index")).ok()?;
+    let (b, checksum) = b.split_at_checked(b.len().checked_sub(NBYTES)?)?;
+    if digest(b) != checksum {
+        return None;
+    }
+    let b = b.strip_prefix(INDEX_MAGIC)?;
+    let (indexed, b) = b.split_at_checked(8)?;
+    let indexed = u64::from_le_bytes(indexed.try_into().ok()?);
+    let (tail, entries) = b.split_at_checked(NBYTES)?;
+    if indexed < MAGIC.len() as u64
+        || indexed > filesize
+        || tail != tail_digest(file, indexed).ok()?
+    {
+        return None;
+    }
+    if !entries.len().is_multiple_of(NBYTES + 16) {
+        return None;
+    }
+    let offsets = entries
+        .chunks_exact(NBYTES + 16)
+        .map(|entry| {
+            let (h, entry) = entry.split_at(NBYTES);
+            let (pos, len) = entry.split_at(8);
+            (
+                h.try_into().unwrap(),
+                (
+                    u64::from_le_bytes(pos.try_into().unwrap()),
+                    u64::from_le_bytes(len.try_into().unwrap()) as usize,
+                ),
+            )
+        })
+        .collect();
+    Some((offsets, indexed))
+}
+
+impl Records {
+    // Open the data file without reading it.
+    fn open(path: PathBuf) -> std::io::Result<Self> {
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(false)
-            .open(path)?;
-        let mut file = std::io::BufReader::new(file);
-        let mut buf = [0; NBYTES + 8];
-        let mut h = [0; NBYTES];
-        let mut l = [0; 8];
-        let filesize = file.seek(std::io::SeekFrom::End(0))?;
-        file.rewind()?;
-        let mut pos: u64 = 0;
-        while pos != filesize {
-            file.read_exact(&mut buf)?;
-            h.copy_from_slice(&buf[..NBYTES]);
-            l.copy_from_slice(&buf[NBYTES..]);
-            let len = u64::from_le_bytes(l);
-            pos += (NBYTES + 8) as u64;
-            offsets.insert(h, (pos, len as usize));
-            pos += len;
-            file.seek(std::io::SeekFrom::Start(pos))?;
+            .open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            map: None,
+            offsets: Offsets::default(),
+            end: 0,
+            indexed: 0,
+        })
+    }
+    // Lock the data file and scan the records that were appended since it was last scanned
+    //
+    // If the file was replaced by another process before the lock was taken, the new file is
+    // opened and read from the start, using its index file. Under an exclusive lock, a record at
+    // the end of the file that is incomplete or fails its checksum cannot be in the middle of
+    // being appended by another process, so it is truncated. Returns the number of records
+    // scanned and the number of bytes truncated. The file is left unlocked if an error occurs.
+    //
+    // * `exclusive` - Take an exclusive lock rather than a shared lock.
+    fn lock(&mut self, exclusive: bool) -> std::io::Result<(usize, u64)> {
+        loop {
+            if exclusive {
+                self.file.lock()?;
+            } else {
+                self.file.lock_shared()?;
+            }
+            if is_current(&self.file, &self.path)? {
+                break;
+            }
+            *self = Self::open(self.path.clone())?;
+        }
+        let result = self.scan(exclusive);
+        if result.is_err() {
+            let _ = self.file.unlock();
         }
+        result
+    }
+    fn scan(&mut self, exclusive: bool) -> std::io::Result<(usize, u64)> {
+        let mut filesize = self.file.seek(std::io::SeekFrom::End(0))?;
+        let mut truncated = 0;
+        if self.end == 0 {
+            let mut magic = Vec::with_capacity(MAGIC.len());
+            self.file.rewind()?;
+            (&self.file)
+                .take(MAGIC.len() as u64)
+                .read_to_end(&mut magic)?;
+            if !MAGIC.starts_with(&magic) || (magic.len() < MAGIC.len() && !exclusive) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "not a stash database file",
+                ));
+            }
+            if magic.len() < MAGIC.len() {
+                // The file was just created, or its creation was interrupted.
+                truncated = filesize;
+                self.map = None;
+                self.file.set_len(0)?;
+                self.file.rewind()?;
+                self.file.write_all(MAGIC)?;
+                self.file.sync_all()?;
+                filesize = MAGIC.len() as u64;
+            }
+            (self.offsets, self.indexed) = read_index(&self.path, &self.file, filesize)
+                .unwrap_or_else(|| (Offsets::default(), MAGIC.len() as u64));
+            self.end = self.indexed;
+        }
+        let (scanned, end) = scan_records(&self.file, &mut self.offsets, self.end, filesize)?;
+        self.end = end;
+        if exclusive && end != filesize {
+            // Windows does not allow a mapped file to be truncated.
+            self.map = None;
+            self.file.set_len(end)?;
+            self.file.sync_all()?;
+            truncated += filesize - end;
+        }
+        Ok((scanned, truncated))
+    }
+    // Scan the records that other processes appended since the file was last scanned.
+    fn refresh(&mut self) -> std::io::Result<()> {
+        self.lock(false)?;
+        self.file.unlock()
+    }
+    // Return the data of a record from the mapped file, mapping the file anew if it grew past the
+    // current mapping.
+    fn slice(&mut self, pos: u64, len: usize) -> std::io::Result<Slice> {
+        let (pos, end) = (pos as usize, pos as usize + len);
+        let map = match &self.map {
+            Some(map) if map.len() >= end => map.clone(),
+            _ => {
+                // Safety: records are never modified once they are written. The file is only
+                // truncated beyond the last valid record, and it is replaced by renaming another
+                // file over it, which leaves the mapped file intact.
+                let map = Arc::new(unsafe { Mmap::map(&self.file)? });
+                self.map = Some(map.clone());
+                map
+            }
+        };
+        if map.len() < end {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Slice { map, pos, len })
+    }
+    // Append a record to the file, which must be locked exclusively, and return its position.
+    fn append(&mut self, record: &[u8]) -> std::io::Result<u64> {
+        let pos = self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file.write_all(record)?;
+        self.end = pos + record.len() as u64;
+        Ok(pos)
+    }
+    // Write the offsets to the index file. The index is written to a temporary file that is
+    // renamed over the index file, so that it is replaced as a whole or not at all. It is not
+    // synced to disk, as an index file that was lost or damaged in a crash is simply ignored.
+    fn write_index(&mut self) -> std::io::Result<()> {
+        let mut b =
+            Vec::with_capacity(INDEX_MAGIC.len() + 8 + (NBYTES + 16) * (self.offsets.len() + 2));
+        b.extend_from_slice(INDEX_MAGIC);
+        b.extend_from_slice(&self.end.to_le_bytes());
+        b.extend_from_slice(&tail_digest(&self.file, self.end)?);
+        for (h, (pos, len)) in &self.offsets {
+            b.extend_from_slice(h);
+            b.extend_from_slice(&pos.to_le_bytes());
+            b.extend_from_slice(&(*len as u64).to_le_bytes());
+        }
+        let checksum = digest(&b);
+        b.extend_from_slice(&checksum);
+        let tmppath = sidecar(&self.path, ".index.tmp");
+        std::fs::write(&tmppath, b)?;
+        std::fs::rename(&tmppath, sidecar(&self.path, ".index"))?;
+        self.indexed = self.end;
+        Ok(())
+    }
+    // Rewrite the live records to a new file that replaces the current file
+    //
+    // The records are copied in their original order to a temporary file next to the database,
+    // which is synced to disk before it is renamed over the database file. A crash before the
+    // rename leaves the original file intact, and a crash after it leaves the compacted file, so
+    // that the database is valid at all times. The new file is locked before it is renamed, so
+    // that it stays locked as long as the file it replaces. The current file is unmapped first, as
+    // Windows does not allow a mapped file to be replaced; for the same reason, compaction fails
+    // there while data that was read from the file is still in use, or while another process has
+    // it mapped. Returns the number of bytes reclaimed.
+    fn compact(&mut self) -> std::io::Result<u64> {
+        let tmppath = sidecar(&self.path, ".compact");
+        let tmpfile = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmppath)?;
+        tmpfile.lock()?;
+        let mut records: Vec<_> = self
+            .offsets
+            .iter()
+            .map(|(h, (pos, len))| (*pos, *len, *h))
+            .collect();
+        records.sort_unstable();
+        let mut offsets = HashMap::default();
+        let mut writer = std::io::BufWriter::new(&tmpfile);
+        writer.write_all(MAGIC)?;
+        let mut newpos = MAGIC.len() as u64;
+        for (pos, len, h) in records {
+            // Records are copied as a whole, including their header and checksum.
+            let reclen = (HEADER + len + CHECKSUM) as u64;
+            self.file
+                .seek(std::io::SeekFrom::Start(pos - HEADER as u64))?;
+            std::io::copy(&mut (&self.file).take(reclen), &mut writer)?;
+            offsets.insert(h, (newpos + HEADER as u64, len));
+            newpos += reclen;
+        }
+        writer.flush()?;
+        drop(writer);
+        tmpfile.sync_all()?;
+        self.map = None;
+        std::fs::rename(&tmppath, &self.path)?;
+        // The rename itself is made durable by syncing the directory that contains the file.
+        #[cfg(unix)]
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all()?,
+            _ => File::open(".")?.sync_all()?,
+        }
+        let oldsize = self.end;
+        self.file = tmpfile;
+        self.offsets = offsets;
+        self.end = newpos;
+        let _ = self.write_index();
+        Ok(oldsize - newpos)
+    }
+}
+
+impl FileDB {
+    // Open a database file, using the index file to avoid scanning the records that it covers.
+    // Records that were not completely written, as a result of a crash, are truncated from the
+    // end of the file. The index is rewritten if any records had to be scanned.
+    pub(crate) fn new(path: PathBuf, functions: FunctionMode) -> std::io::Result<Self> {
+        let mut records = Records::open(path)?;
+        let (scanned, truncated) = records.lock(true)?;
+        if records.indexed != records.end {
+            // Failing to write the index only affects the time it takes to open the file.
+            let _ = records.write_index();
+        }
+        records.file.unlock()?;
         Ok(Self {
-            file: file.into_inner(),
-            offsets,
+            records: Mutex::new(records),
+            recovery: Recovery { scanned, truncated },
+            functions,
         })
     }
+    fn records(&self) -> MutexGuard<'_, Records> {
+        self.records.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    // Run a function on the records while holding an exclusive lock on the data file.
+    fn exclusive<T, E: From<std::io::Error>>(
+        &mut self,
+        f: impl FnOnce(&mut Records) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let records = self
+            .records
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (_, truncated) = records.lock(true)?;
+        self.recovery.truncated += truncated;
+        let result = f(records);
+        records.file.unlock()?;
+        result
+    }
+    // Compact the data file while holding an exclusive lock on it.
+    fn compact(&mut self) -> std::io::Result<u64> {
+        self.exclusive(Records::compact)
+    }
+    // Look up the position and length of the data of a record, scanning the records that other
+    // processes appended if the hash is not known yet.
+    fn lookup(records: &mut Records, h: Key) -> MappingResult<(u64, usize)> {
+        if !records.offsets.contains_key(&h) {
+            records.refresh()?;
+        }
+        records
+            .offsets
+            .get(&h)
+            .copied()
+            .ok_or(MappingError::NotFound(h))
+    }
+}
+
+// The index is brought up to date when the database is closed, but only if the lock can be taken
+// without waiting, so that closing the database cannot block on another process.
+impl Drop for FileDB {
+    fn drop(&mut self) {
+        let records = self
+            .records
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if records.end == records.indexed || records.file.try_lock().is_err() {
+            return;
+        }
+        if is_current(&records.file, &records.path).unwrap_or(false) && records.scan(true).is_ok() {
+            let _ = records.write_index();
+        }
+        let _ = records.file.unlock();
+    }
 }
 
 impl Put for FileDB {
     fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
-        match self.offsets.entry(h) {
-            Entry::Occupied(e) => {
-                let (pos, len) = e.get();
-                let mut data = b.as_ref();
-                if data.len() != *len {
+        let data = b.as_ref();
+        // Other processes may have stored, deleted or compacted away the blob since the file was
+        // last scanned, so its presence is only decided once the file is locked and scanned.
+        self.exclusive(|records| {
+            if let Some(&(pos, len)) = records.offsets.get(&h) {
+                if !content_equals(&*records.slice(pos, len)?, data)? {
                     return Err(MappingError::Collision(h));
                 }
-                self.file.seek(std::io::SeekFrom::Start(*pos))?;
-                const NBUF: usize = 131072; // 128 KB
-                let mut readbuf = [0; NBUF];
-                let mut chunk;
-                while !data.is_empty() {
-                    let n = self
-                        .file
-                        .read(&mut readbuf[..std::cmp::min(NBUF, data.len())])?;
-                    (chunk, data) = data.split_at(n);
-                    if &readbuf[..n] != chunk {
-                        return Err(MappingError::Collision(h));
-                    }
-                }
+            } else {
+                let pos = records.append(&new_record(&h, Some(data)))?;
+                records.offsets.insert(h, (pos + HEADER as u64, data.len()));
             }
-            Entry::Vacant(e) => {
-                let data = b.as_ref();
-                let pos = self.file.seek(std::io::SeekFrom::End(0))?;
-                let mut writebuf = [0; NBYTES + 8];
-                writebuf[..NBYTES].copy_from_slice(&h);
-                writebuf[NBYTES..].copy_from_slice(&(data.len() as u64).to_le_bytes());
-                self.file.write_all(&writebuf)?;
-                self.file.write_all(data)?;
-                e.insert_entry((pos + (NBYTES + 8) as u64, data.len()));
+            Ok(())
+        })
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        // The file is append-only, so the deletion is recorded as a tombstone that is applied when
+        // the file is loaded.
+        self.exclusive(|records| {
+            if records.offsets.remove(&h).is_none() {
+                return Err(MappingError::NotFound(h));
             }
-        }
-        Ok(())
+            records.append(&new_record(&h, None))?;
+            Ok(())
+        })
     }
 }
 
 impl Get for FileDB {
     fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
-        if let Some((pos, len)) = self.offsets.get(&h) {
-            let mut file = self.file.try_clone()?;
-            file.seek(std::io::SeekFrom::Start(*pos))?;
-            let mut v = vec![0; *len];
-            file.read_exact(&mut v)?;
-            Ok(v)
-        } else {
-            Err(MappingError::NotFound(h))
+        let mut records = self.records();
+        let (pos, len) = Self::lookup(&mut records, h)?;
+        Ok(records.slice(pos, len)?)
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        match Self::lookup(&mut self.records(), h) {
+            Err(MappingError::NotFound(_)) => Ok(false),
+            result => result.map(|_| true),
         }
     }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        let mut records = self.records();
+        records.refresh()?;
+        Ok(records.offsets.keys().copied().collect())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        let mut records = self.records();
+        records.refresh()?;
+        Ok(records.offsets.len())
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        Ok(Self::lookup(&mut self.records(), h)?.1)
+    }
 }
 
 #[pymethods]
 impl FileDB {
     #[new]
-    fn py_new(path: PathBuf) -> PyResult<Self> {
-        Ok(Self::new(path)?)
+    #[pyo3(signature = (path, *, functions = FunctionMode::Name))]
+    fn py_new(path: PathBuf, functions: FunctionMode) -> PyResult<Self> {
+        Ok(Self::new(path, functions)?)
     }
-    fn hash<'py>(&mut self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
-        serialize(obj, self)
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, self, functions.unwrap_or(self.functions))
     }
     fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
         deserialize(obj, self)
     }
+    #[getter]
+    fn recovery(&self) -> Recovery {
+        self.recovery.clone()
+    }
+    #[pyo3(name = "compact")]
+    fn py_compact(&mut self) -> PyResult<u64> {
+        Ok(self.compact()?)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        Ok(collect(self, &extract_roots(roots)?, dry_run)?)
+    }
 }
@@ -1,67 +1,614 @@
-use pyo3::{pyclass, pymethods, types::PyBytes, Bound, PyAny, PyResult};
+use pyo3::{
+    exceptions::PyValueError,
+    pyclass, pymethods,
+    types::{PyBytes, PyIterator},
+    Bound, PyAny, PyResult, Python,
+};
 
 use crate::{
     deserialize::deserialize,
-    hex::Hex,
-    mapping::{Get, Key, MappingError, MappingResult, Put, NBYTES},
-    serialize::serialize,
+    gc::{collect, extract_roots, Report},
+    hex::{self, Hex},
+    mapping::{
+        content_equals, digest, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put,
+        NBYTES,
+    },
+    nohash::NoHashBuilder,
+    serialize::{serialize, FunctionMode},
 };
 
 use std::{
+    collections::HashSet,
     fmt::Write as FmtWrite,
     fs::File,
-    io::{Read, Result as IoResult, Write},
+    io::{Read, Seek, Write},
     ops::Deref,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
 };
 
+// The name of the file in the root directory that records the layout of the store.
+const LAYOUT: &str = "layout";
+
+// The name of the directory in the root directory that holds the pack files.
+const PACKS: &str = "pack";
+
+// The first bytes of a pack file and of its index file, which include the version of their format.
+const PACK_MAGIC: &[u8; 8] = b"STASHPK1";
+const PACK_INDEX_MAGIC: &[u8; 8] = b"STASHPI1";
+
+// The age after which the temporary file of a write is presumed to be left behind by a process that
+// was interrupted, rather than to be in use.
+const STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// A set of blobs stored in a single file, in the style of git packfiles. Every pack consists of
+// three files that share a name: the pack file with the magic bytes followed by the blobs, the
+// index file with the magic bytes, the hash, position and length of every blob in order of hash,
+// and a checksum over all of the preceding, and optionally a file with the hashes of the blobs
+// that were deleted from the pack. The name of a pack is formed from the checksum of its index.
+struct Pack {
+    base: PathBuf,
+    file: File,
+    entries: Vec<(Key, u64, usize)>,
+    deleted: HashSet<Key, NoHashBuilder>,
+    // The length of the part of the deletion file that was read.
+    deleted_len: u64,
+}
+
+impl Pack {
+    // Open the pack with the given path without extension.
+    fn open(base: PathBuf) -> std::io::Result<Self> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid pack index");
+        let b = std::fs::read(base.with_extension("idx"))?;
+        let (b, checksum) = b
+            .split_at_checked(b.len().saturating_sub(NBYTES))
+            .ok_or_else(invalid)?;
+        if digest(b) != checksum {
+            return Err(invalid());
+        }
+        let entries = b.strip_prefix(PACK_INDEX_MAGIC).ok_or_else(invalid)?;
+        if !entries.len().is_multiple_of(NBYTES + 16) {
+            return Err(invalid());
+        }
+        let entries = entries
+            .chunks_exact(NBYTES + 16)
+            .map(|entry| {
+                let (h, entry) = entry.split_at(NBYTES);
+                let (pos, len) = entry.split_at(8);
+                (
+                    h.try_into().unwrap(),
+                    u64::from_le_bytes(pos.try_into().unwrap()),
+                    u64::from_le_bytes(len.try_into().unwrap()) as usize,
+                )
+            })
+            .collect();
+        let mut pack = Self {
+            file: File::open(base.with_extension("pack"))?,
+            base,
+            entries,
+            deleted: HashSet::default(),
+            deleted_len: 0,
+        };
+        if !pack.refresh()? {
+            return Err(std::io::ErrorKind::NotFound.into());
+        }
+        Ok(pack)
+    }
+    // Read the deletions that other processes recorded since the last time, and return whether
+    // the pack still exists. The deletion file is read before the index is checked, so that the
+    // deletions from a pack that is removed in the meantime are not missed.
+    fn refresh(&mut self) -> std::io::Result<bool> {
+        match File::open(self.base.with_extension("del")) {
+            Ok(mut f) => {
+                f.seek(std::io::SeekFrom::Start(self.deleted_len))?;
+                let mut b = Vec::new();
+                f.read_to_end(&mut b)?;
+                let chunks = b.chunks_exact(NBYTES);
+                self.deleted_len += (b.len() - chunks.remainder().len()) as u64;
+                self.deleted
+                    .extend(chunks.map(|h| Key::try_from(h).unwrap()));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.base.with_extension("idx").try_exists()
+    }
+    // Look up the position and length of a blob that was not deleted from the pack.
+    fn find(&self, h: &Key) -> Option<(u64, usize)> {
+        if self.deleted.contains(h) {
+            return None;
+        }
+        let i = self.entries.binary_search_by_key(h, |(h, _, _)| *h).ok()?;
+        let (_, pos, len) = self.entries[i];
+        Some((pos, len))
+    }
+    fn read(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut file = &self.file;
+        file.seek(std::io::SeekFrom::Start(pos))?;
+        let mut v = vec![0; len];
+        file.read_exact(&mut v)?;
+        Ok(v)
+    }
+    // Record the deletion of a blob from the pack. This must be done under the lock of the pack
+    // directory, so that the pack cannot be removed in the meantime, leaving an orphan deletion
+    // file behind.
+    fn delete(&mut self, h: Key) -> std::io::Result<()> {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.base.with_extension("del"))?
+            .write_all(&h)?;
+        self.deleted.insert(h);
+        Ok(())
+    }
+    fn remove(&self) -> std::io::Result<()> {
+        // The index is removed first, as it is what makes the pack visible.
+        std::fs::remove_file(self.base.with_extension("idx"))?;
+        for ext in ["del", "pack"] {
+            match std::fs::remove_file(self.base.with_extension(ext)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Bring the list of packs up to date with the index files in the pack directory, keeping the packs
+// that were already opened with the deletions that were recorded since.
+fn load_packs(root: &Path, packs: &mut Vec<Pack>) -> std::io::Result<()> {
+    let dir = match std::fs::read_dir(root.join(PACKS)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            packs.clear();
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let mut bases = Vec::new();
+    for entry in dir {
+        let path = entry?.path();
+        let is_index = path.extension().is_some_and(|ext| ext == "idx");
+        let is_pack = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| stem.starts_with("pack-"));
+        if is_index && is_pack {
+            bases.push(path.with_extension(""));
+        }
+    }
+    bases.sort_unstable();
+    let mut old = std::mem::take(packs);
+    for base in bases {
+        match old.iter().position(|pack| pack.base == base) {
+            Some(i) => {
+                let mut pack = old.swap_remove(i);
+                if pack.refresh()? {
+                    packs.push(pack);
+                }
+            }
+            // A pack that was removed by another process in the meantime is skipped.
+            None => match Pack::open(base) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                pack => packs.push(pack?),
+            },
+        }
+    }
+    Ok(())
+}
+
+// Every blob is stored in `depth` levels of nested directories, each named after the next `width`
+// bytes of its hash, in a file named after the remaining bytes.
+//
+// Blobs can also be moved into pack files, which are searched when a blob is not found in its own
+// file. The packs are loaded when the store is opened, and loaded again when a blob is not found,
+// as other processes may have packed it in the meantime. Likewise, the deletions from a pack are
+// read again before a blob that is found in it is trusted.
 #[pyclass(name = "FsDB")]
-pub struct FsDB(PathBuf);
+pub struct FsDB {
+    root: PathBuf,
+    #[pyo3(get)]
+    depth: usize,
+    #[pyo3(get)]
+    width: usize,
+    packs: Mutex<Vec<Pack>>,
+    functions: FunctionMode,
+}
 
 impl FsDB {
+    // Open a store, creating it if it does not exist
+    //
+    // The layout of a new store is recorded in a marker file in its root directory. The layout of
+    // an existing store is read from the marker file, and it is an error for it to differ from the
+    // requested layout. A store without a marker file that is not empty was created by an earlier
+    // version, whose blobs cannot be read, and is refused.
+    //
+    // * `root` - Root directory of the store.
+    // * `depth` - Number of directory levels, or None for that of an existing store or 1.
+    // * `width` - Number of hash bytes per directory level, or None for that of an existing store
+    //   or 1.
+    // * `functions` - How functions are hashed by default.
+    pub(crate) fn new(
+        root: PathBuf,
+        depth: Option<usize>,
+        width: Option<usize>,
+        functions: FunctionMode,
+    ) -> PyResult<Self> {
+        std::fs::create_dir_all(&root)?;
+        let marker = root.join(LAYOUT);
+        let layout = match std::fs::read_to_string(&marker) {
+            Ok(s) => parse_layout(&s)
+                .filter(|&(depth, width)| is_valid_layout(depth, width))
+                .ok_or_else(|| {
+                    PyValueError::new_err(format!("invalid layout file {}", marker.display()))
+                })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // The temporary layout files of other processes that are creating the store at the
+                // same time are not entries of an earlier version.
+                for entry in std::fs::read_dir(&root)? {
+                    if !entry?.file_name().to_string_lossy().starts_with(LAYOUT) {
+                        return Err(PyValueError::new_err(format!(
+                            "{} is not empty and has no layout file; it was created by an earlier \
+                             version of stash, whose blobs cannot be read",
+                            root.display()
+                        )));
+                    }
+                }
+                let layout = (depth.unwrap_or(1), width.unwrap_or(1));
+                if !is_valid_layout(layout.0, layout.1) {
+                    return Err(PyValueError::new_err("invalid layout"));
+                }
+                let s = format!("depth={} width={}\n", layout.0, layout.1);
+                match write_new(&marker, s.as_bytes()) {
+                    Ok(()) => {}
+                    // Another process created the store at the same time.
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        return Self::new(root, depth, width, functions);
+                    }
+                    Err(e) => Err(e)?,
+                }
+                layout
+            }
+            Err(e) => Err(e)?,
+        };
+        if depth.is_some_and(|depth| depth != layout.0)
+            || width.is_some_and(|width| width != layout.1)
+        {
+            return Err(PyValueError::new_err(format!(
+                "store has layout depth={} width={}",
+                layout.0, layout.1
+            )));
+        }
+        let mut packs = Vec::new();
+        load_packs(&root, &mut packs)?;
+        Ok(Self {
+            root,
+            depth: layout.0,
+            width: layout.1,
+            packs: Mutex::new(packs),
+            functions,
+        })
+    }
     fn path_for(&self, h: &Key) -> PathBuf {
-        let capacity = self.0.as_os_str().len() + NBYTES * 2 + 2;
+        let capacity = self.root.as_os_str().len() + NBYTES * 2 + self.depth + 1;
         let mut path = PathBuf::with_capacity(capacity);
         let s = path.as_mut_os_string();
-        s.push(self.0.as_os_str());
-        let (left, right) = h.split_at(1);
-        write!(
-            s,
-            "{}{}{}{}",
-            std::path::MAIN_SEPARATOR,
-            Hex(left),
-            std::path::MAIN_SEPARATOR,
-            Hex(right)
-        )
-        .unwrap();
+        s.push(self.root.as_os_str());
+        let (dirs, file) = h.split_at(self.depth * self.width);
+        for dir in dirs.chunks(self.width) {
+            write!(s, "{}{}", std::path::MAIN_SEPARATOR, Hex(dir)).unwrap();
+        }
+        write!(s, "{}{}", std::path::MAIN_SEPARATOR, Hex(file)).unwrap();
         path
     }
 }
 
-fn file_equals(mut file: File, mut data: &[u8]) -> IoResult<bool> {
-    let mut buf = [0; 131072]; // 128 KB; https://eklitzke.org/efficient-file-copying-on-linux
-    loop {
-        let n = file.read(&mut buf)?;
-        if n == 0 {
-            return Ok(data.is_empty());
+impl FsDB {
+    fn packs(&self) -> MutexGuard<'_, Vec<Pack>> {
+        self.packs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    // Serialize packing and deleting between processes by the lock file in the pack directory.
+    fn lock(&self) -> std::io::Result<File> {
+        let dir = self.root.join(PACKS);
+        std::fs::create_dir_all(&dir)?;
+        let lock = File::create(dir.join("lock"))?;
+        lock.lock()?;
+        Ok(lock)
+    }
+    // Look up a blob in the packs, loading the packs again if it is not found or one of the packs
+    // that it was found in was removed, and return the pack, position and length of the blob.
+    fn find_packed<'a>(
+        &self,
+        packs: &'a mut Vec<Pack>,
+        h: &Key,
+    ) -> std::io::Result<Option<(&'a mut Pack, u64, usize)>> {
+        let mut stale = false;
+        for pack in packs.iter_mut() {
+            if pack.find(h).is_some() && !pack.refresh()? {
+                stale = true;
+            }
         }
-        if n > data.len() || buf[..n] != data[..n] {
-            return Ok(false);
+        if stale || !packs.iter().any(|pack| pack.find(h).is_some()) {
+            load_packs(&self.root, packs)?;
+        }
+        Ok(packs
+            .iter_mut()
+            .find_map(|pack| pack.find(h).map(|(pos, len)| (pack, pos, len))))
+    }
+    fn get_packed(&self, h: Key) -> MappingResult<Vec<u8>> {
+        let mut packs = self.packs();
+        match self.find_packed(&mut packs, &h)? {
+            Some((pack, pos, len)) => Ok(pack.read(pos, len)?),
+            None => Err(MappingError::NotFound(h)),
+        }
+    }
+    // List the hashes of the blobs that are stored in their own file. Entries that do not follow
+    // the layout of the store are skipped.
+    fn loose_keys(&self) -> std::io::Result<Vec<Key>> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![(self.root.clone(), Vec::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let Some(part) = entry.file_name().to_str().and_then(hex::parse) else {
+                    continue;
+                };
+                let name = [prefix.as_slice(), &part].concat();
+                if prefix.len() < self.depth * self.width {
+                    if part.len() == self.width && entry.file_type()?.is_dir() {
+                        dirs.push((entry.path(), name));
+                    }
+                } else if let Ok(h) = name.try_into() {
+                    keys.push(h);
+                }
+            }
+        }
+        Ok(keys)
+    }
+    // Move all blobs into a single new pack
+    //
+    // The blobs that are stored in their own files and the blobs that were not deleted from the
+    // existing packs are written to a new pack, which is synced to disk before its index file is
+    // renamed into place. Only then are the files of the blobs and the existing packs removed, so
+    // that a crash leaves every blob in the store, possibly more than once. Packing is serialized
+    // between processes by a lock file in the pack directory. The temporary files that were left
+    // behind by interrupted writes are removed first. Returns the number of blobs in the new pack,
+    // or zero if there was nothing to pack.
+    fn pack(&self) -> std::io::Result<usize> {
+        let _lock = self.lock()?;
+        self.remove_stale()?;
+        let dir = self.root.join(PACKS);
+        let mut packs = self.packs();
+        load_packs(&self.root, &mut packs)?;
+        let loose = self.loose_keys()?;
+        if loose.is_empty() && packs.len() <= 1 && packs.iter().all(|p| p.deleted.is_empty()) {
+            return Ok(0);
         }
-        data = &data[n..];
+        let tmppath = dir.join(format!("tmp-{}.pack", std::process::id()));
+        let tmpfile = File::create(&tmppath)?;
+        let mut writer = std::io::BufWriter::new(&tmpfile);
+        writer.write_all(PACK_MAGIC)?;
+        let mut pos = PACK_MAGIC.len() as u64;
+        let mut entries = Vec::new();
+        let mut seen = HashSet::<Key, NoHashBuilder>::default();
+        let mut packed = Vec::new();
+        for h in loose {
+            let b = match std::fs::read(self.path_for(&h)) {
+                // A blob that was deleted by another process in the meantime is skipped.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                b => b?,
+            };
+            writer.write_all(&b)?;
+            entries.push((h, pos, b.len()));
+            pos += b.len() as u64;
+            seen.insert(h);
+            packed.push(h);
+        }
+        for pack in packs.iter() {
+            for (h, _, _) in &pack.entries {
+                let Some((oldpos, len)) = pack.find(h) else {
+                    continue;
+                };
+                if seen.insert(*h) {
+                    writer.write_all(&pack.read(oldpos, len)?)?;
+                    entries.push((*h, pos, len));
+                    pos += len as u64;
+                }
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        tmpfile.sync_all()?;
+        entries.sort_unstable();
+        let mut index =
+            Vec::with_capacity(PACK_INDEX_MAGIC.len() + (NBYTES + 16) * (entries.len() + 1));
+        index.extend_from_slice(PACK_INDEX_MAGIC);
+        for (h, pos, len) in &entries {
+            index.extend_from_slice(h);
+            index.extend_from_slice(&pos.to_le_bytes());
+            index.extend_from_slice(&(*len as u64).to_le_bytes());
+        }
+        let checksum = digest(&index);
+        index.extend_from_slice(&checksum);
+        let base = dir.join(format!("pack-{}", Hex(&checksum)));
+        std::fs::rename(&tmppath, base.with_extension("pack"))?;
+        let tmppath = dir.join(format!("tmp-{}.idx", std::process::id()));
+        let mut tmpfile = File::create(&tmppath)?;
+        tmpfile.write_all(&index)?;
+        tmpfile.sync_all()?;
+        std::fs::rename(&tmppath, base.with_extension("idx"))?;
+        // The renames themselves are made durable by syncing the pack directory.
+        sync_dir(&dir)?;
+        for pack in packs.drain(..) {
+            if pack.base != base {
+                pack.remove()?;
+            }
+        }
+        for h in packed {
+            let path = self.path_for(&h);
+            // A blob that was deleted by another process in the meantime is already gone.
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            // Directories that were emptied are removed as well, which fails for the others.
+            for dir in path.ancestors().skip(1).take(self.depth) {
+                if std::fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        packs.push(Pack::open(base)?);
+        Ok(entries.len())
+    }
+    // Remove the temporary files that were left behind by interrupted writes. The temporary files
+    // of packing are stale as soon as the lock is held, and those of blobs once they are older than
+    // `STALE_AGE`.
+    fn remove_stale(&self) -> std::io::Result<()> {
+        // A file that was removed by another process in the meantime is already gone.
+        let remove = |path: PathBuf| match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        for entry in std::fs::read_dir(self.root.join(PACKS))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("tmp-") {
+                remove(entry.path())?;
+            }
+        }
+        let mut dirs = vec![(self.root.clone(), 0)];
+        while let Some((dir, level)) = dirs.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name.ends_with(".tmp") {
+                    let modified = entry.metadata()?.modified()?;
+                    if modified.elapsed().is_ok_and(|age| age > STALE_AGE) {
+                        remove(entry.path())?;
+                    }
+                } else if level < self.depth
+                    && hex::parse(&name).is_some_and(|part| part.len() == self.width)
+                    && entry.file_type()?.is_dir()
+                {
+                    dirs.push((entry.path(), level + 1));
+                }
+            }
+        }
+        Ok(())
     }
 }
 
+// Whether a layout leaves at least one byte of the hash for the file name.
+fn is_valid_layout(depth: usize, width: usize) -> bool {
+    width != 0 && depth.checked_mul(width).is_some_and(|n| n < NBYTES)
+}
+
+// Parse the contents of a layout file into the depth and width, or return None if it is invalid.
+fn parse_layout(s: &str) -> Option<(usize, usize)> {
+    let (depth, width) = s.trim_end().split_once(' ')?;
+    let depth = depth.strip_prefix("depth=")?.parse().ok()?;
+    let width = width.strip_prefix("width=")?.parse().ok()?;
+    Some((depth, width))
+}
+
+// Write a new file as a whole or not at all
+//
+// The data is written to a temporary file that is synced to disk before it is moved into place, so
+// that neither a crash nor a concurrent reader can observe a partially written file. The temporary
+// file is named after the process, and its name does not parse as a hash, so that it is skipped by
+// `keys`. It is moved by linking rather than renaming where possible, which fails with
+// `AlreadyExists` rather than replaces the file if another process created it in the meantime.
+// Finally the directory is synced, so that the new file is durable once this function returns.
+pub(crate) fn write_new(path: &Path, b: &[u8]) -> std::io::Result<()> {
+    let mut tmppath = path.as_os_str().to_owned();
+    write!(tmppath, ".{}.tmp", std::process::id()).unwrap();
+    let mut f = File::create(&tmppath)?;
+    f.write_all(b)?;
+    f.sync_all()?;
+    let result = match std::fs::hard_link(&tmppath, path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => std::fs::rename(&tmppath, path),
+        result => result,
+    };
+    let _ = std::fs::remove_file(&tmppath);
+    result?;
+    sync_dir(path.parent().unwrap())
+}
+
+// Create a directory and any of its parents that do not exist yet, syncing the directory that each
+// one is created in so that the new directories are durable.
+fn create_dir_synced(dir: &Path) -> std::io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    let parent = dir.parent().unwrap();
+    create_dir_synced(parent)?;
+    // A directory that another process created in the meantime may not be synced yet either.
+    match std::fs::create_dir(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    sync_dir(parent)
+}
+
+// Sync a directory, which makes the creation, renaming and removal of its entries durable. This is
+// only possible, and needed, on unix.
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// Verify that a stored blob has the same content as the blob that is being stored.
+fn verify(stored: impl Read, h: Key, b: &[u8]) -> MappingResult<()> {
+    if !content_equals(stored, b)? {
+        return Err(MappingError::Collision(h));
+    }
+    Ok(())
+}
+
 impl Put for FsDB {
     fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
         let path = self.path_for(&h);
         if let Ok(f) = File::open(&path) {
-            if !file_equals(f, b.as_ref())? {
-                return Err(MappingError::Collision(h));
+            return verify(std::io::BufReader::new(f), h, b.as_ref());
+        }
+        match self.get_packed(h) {
+            Ok(stored) => return verify(stored.as_slice(), h, b.as_ref()),
+            Err(MappingError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        // A blob that another process stored in the meantime is verified instead.
+        create_dir_synced(path.parent().unwrap())?;
+        match write_new(&path, b.as_ref()) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                verify(std::io::BufReader::new(File::open(&path)?), h, b.as_ref())
             }
-        } else {
-            std::fs::create_dir_all(path.parent().unwrap())?;
-            File::create_new(&path)?.write_all(b.as_ref())?;
+            result => Ok(result?),
+        }
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        // A blob can be both in its own file and in packs if packing was interrupted, in which
+        // case it is deleted from all of them. The lock keeps other processes from packing the
+        // blob or removing its packs in the meantime.
+        let _lock = self.lock()?;
+        let mut found = match std::fs::remove_file(self.path_for(&h)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            result => result.map(|_| true)?,
+        };
+        let mut packs = self.packs();
+        load_packs(&self.root, &mut packs)?;
+        for pack in packs.iter_mut() {
+            if pack.find(&h).is_some() {
+                pack.delete(h)?;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(MappingError::NotFound(h));
         }
         Ok(())
     }
@@ -69,26 +616,88 @@ impl Put for FsDB {
 
 impl Get for FsDB {
     fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
-        std::fs::read(self.path_for(&h)).map_err(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                MappingError::NotFound(h)
-            } else {
-                e.into()
+        match std::fs::read(self.path_for(&h)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.get_packed(h),
+            b => Ok(b?),
+        }
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Ok(self.path_for(&h).try_exists()? || self.find_packed(&mut self.packs(), &h)?.is_some())
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        let mut keys: HashSet<Key, NoHashBuilder> = self.loose_keys()?.into_iter().collect();
+        let mut packs = self.packs();
+        load_packs(&self.root, &mut packs)?;
+        for pack in packs.iter() {
+            keys.extend(
+                pack.entries
+                    .iter()
+                    .map(|(h, _, _)| *h)
+                    .filter(|h| !pack.deleted.contains(h)),
+            );
+        }
+        Ok(keys.into_iter().collect())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self.keys()?.len())
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        match std::fs::metadata(self.path_for(&h)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match self.find_packed(&mut self.packs(), &h)? {
+                    Some((_, _, len)) => Ok(len),
+                    None => Err(MappingError::NotFound(h)),
+                }
             }
-        })
+            metadata => Ok(metadata?.len() as usize),
+        }
     }
 }
 
 #[pymethods]
 impl FsDB {
     #[new]
-    fn py_new(path: PathBuf) -> Self {
-        Self(path)
+    #[pyo3(signature = (path, *, depth = None, width = None, functions = FunctionMode::Name))]
+    fn py_new(
+        path: PathBuf,
+        depth: Option<usize>,
+        width: Option<usize>,
+        functions: FunctionMode,
+    ) -> PyResult<Self> {
+        Self::new(path, depth, width, functions)
     }
-    fn hash<'py>(&mut self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
-        serialize(obj, self)
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, self, functions.unwrap_or(self.functions))
     }
     fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
         deserialize(obj, self)
     }
+    #[pyo3(name = "pack")]
+    fn py_pack(&self) -> PyResult<usize> {
+        Ok(self.pack()?)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        Ok(collect(self, &extract_roots(roots)?, dry_run)?)
+    }
 }
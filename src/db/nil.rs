@@ -1,8 +1,8 @@
 use pyo3::{pyfunction, types::PyBytes, Bound, PyAny, PyResult};
 
 use crate::{
-    mapping::{Key, MappingResult, Put},
-    serialize::serialize,
+    mapping::{Key, MappingError, MappingResult, Put},
+    serialize::{serialize, FunctionMode},
 };
 
 pub struct Nil;
@@ -11,9 +11,16 @@ impl Put for Nil {
     fn put(&mut self, _h: Key, _b: impl AsRef<[u8]>) -> MappingResult<()> {
         Ok(())
     }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        Err(MappingError::NotFound(h))
+    }
 }
 
 #[pyfunction]
-pub fn hash<'py>(obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
-    serialize(obj, &mut Nil)
+#[pyo3(signature = (obj, *, functions = FunctionMode::Name))]
+pub fn hash<'py>(
+    obj: &Bound<'py, PyAny>,
+    functions: FunctionMode,
+) -> PyResult<Bound<'py, PyBytes>> {
+    serialize(obj, &mut Nil, functions)
 }
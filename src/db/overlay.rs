@@ -0,0 +1,158 @@
+use pyo3::{
+    prelude::*,
+    types::{PyBytes, PyIterator},
+};
+
+use crate::{
+    deserialize::deserialize,
+    gc::{collect_from, extract_roots, Report},
+    mapping::{content_equals, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put},
+    nohash::NoHashBuilder,
+    serialize::{serialize, FunctionMode},
+};
+
+use super::tiered::Tier;
+
+use std::{collections::HashSet, ops::Deref};
+
+// A writable store on top of a read-only base store. Blobs are read from the top if it has them
+// and from the base otherwise, and they are written to the top unless the base already has them,
+// in which case they are verified against the base instead. Blobs can only be deleted from the
+// top.
+pub struct Layers<M> {
+    top: M,
+    base: M,
+}
+
+impl<M> Layers<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    pub fn new(top: M, base: M) -> Self {
+        Self { top, base }
+    }
+    // List the hashes of the blobs in the top.
+    pub fn top_keys(&self) -> MappingResult<Vec<Key>> {
+        Get::keys(&&self.top)
+    }
+}
+
+impl Layers<Tier> {
+    // Whether a blob may be lost, which is up to the top, as only the top is written.
+    pub(crate) fn may_evict(&self) -> bool {
+        self.top.may_evict()
+    }
+}
+
+impl<M> Put for Layers<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
+        if !Get::contains(&&self.top, h)? {
+            match Get::get(&&self.base, h) {
+                Ok(stored) => {
+                    if !content_equals(&*stored, b.as_ref())? {
+                        return Err(MappingError::Collision(h));
+                    }
+                    return Ok(());
+                }
+                Err(MappingError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Put::put(&mut &self.top, h, b)
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        match Put::delete(&mut &self.top, h) {
+            Err(MappingError::NotFound(_)) if Get::contains(&&self.base, h)? => Err(
+                std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read-only base").into(),
+            ),
+            result => result,
+        }
+    }
+}
+
+impl<M> Get for Layers<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
+        match Get::get(&&self.top, h) {
+            Err(MappingError::NotFound(_)) => Ok(Get::get(&&self.base, h)?.to_vec()),
+            b => Ok(b?.to_vec()),
+        }
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Ok(Get::contains(&&self.top, h)? || Get::contains(&&self.base, h)?)
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        let mut keys: HashSet<Key, NoHashBuilder> = Get::keys(&&self.top)?.into_iter().collect();
+        keys.extend(Get::keys(&&self.base)?);
+        Ok(keys.into_iter().collect())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self.keys()?.len())
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        match Get::size(&&self.top, h) {
+            Err(MappingError::NotFound(_)) => Get::size(&&self.base, h),
+            result => result,
+        }
+    }
+}
+
+#[pyclass(name = "Overlay")]
+pub struct Overlay {
+    pub(crate) layers: Layers<Tier>,
+    functions: FunctionMode,
+}
+
+#[pymethods]
+impl Overlay {
+    #[new]
+    #[pyo3(signature = (top, base, *, functions = FunctionMode::Name))]
+    fn py_new(top: Tier, base: Tier, functions: FunctionMode) -> Self {
+        Self {
+            layers: Layers::new(top, base),
+            functions,
+        }
+    }
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, &mut self.layers, functions.unwrap_or(self.functions))
+    }
+    fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
+        deserialize(obj, &self.layers)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.layers.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.layers.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.layers.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.layers.len()?)
+    }
+    // Only the blobs in the top are removed, while the blobs they reference may be in the base.
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        let keys = self.layers.top_keys()?;
+        Ok(collect_from(
+            &mut self.layers,
+            keys,
+            &extract_roots(roots)?,
+            dry_run,
+        )?)
+    }
+}
@@ -1,15 +1,17 @@
 use pyo3::{
+    exceptions::PyKeyError,
     pyclass, pymethods,
-    types::{PyAnyMethods, PyBytes, PyBytesMethods},
-    Bound, PyAny, PyObject, PyResult,
+    types::{PyAnyMethods, PyBytes, PyBytesMethods, PyIterator},
+    Bound, PyAny, PyErr, PyObject, PyResult, Python,
 };
 
 use std::ops::Deref;
 
 use crate::{
     deserialize::deserialize,
-    mapping::{Get, Key, MappingError, MappingResult, Put},
-    serialize::serialize,
+    gc::{collect, extract_roots, Report},
+    mapping::{content_equals, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put},
+    serialize::{serialize, FunctionMode},
 };
 
 struct PyBytesWrapper<'py>(Bound<'py, PyBytes>);
@@ -21,10 +23,21 @@ impl Deref for PyBytesWrapper<'_> {
     }
 }
 
+// Map the key error of a mapping operation to the not-found error of the database.
+fn not_found(py: Python<'_>, h: Key) -> impl FnOnce(PyErr) -> MappingError + '_ {
+    move |e| {
+        if e.is_instance_of::<PyKeyError>(py) {
+            MappingError::NotFound(h)
+        } else {
+            e.into()
+        }
+    }
+}
+
 impl Put for &Bound<'_, PyAny> {
     fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
         if let Ok(existing) = self.get_item(PyBytes::new(self.py(), &h)) {
-            if existing.downcast_exact::<PyBytes>()?.as_bytes() != b.as_ref() {
+            if !content_equals(existing.downcast_exact::<PyBytes>()?.as_bytes(), b.as_ref())? {
                 return Err(MappingError::Collision(h));
             }
         } else {
@@ -35,33 +48,88 @@ impl Put for &Bound<'_, PyAny> {
         }
         Ok(())
     }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        self.del_item(PyBytes::new(self.py(), &h))
+            .map_err(not_found(self.py(), h))
+    }
 }
 
 impl Get for &Bound<'_, PyAny> {
     fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
         let item = self
-            .get_item(PyBytes::new(self.py(), &h))?
+            .get_item(PyBytes::new(self.py(), &h))
+            .map_err(not_found(self.py(), h))?
             .downcast_exact::<PyBytes>()?
             .clone();
         Ok(PyBytesWrapper(item))
     }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Ok(PyAnyMethods::contains(*self, PyBytes::new(self.py(), &h))?)
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        // Keys that are not hashes are skipped.
+        let mut keys = Vec::new();
+        for key in self.try_iter()? {
+            if let Ok(b) = key?.downcast_exact::<PyBytes>() {
+                if let Ok(h) = b.as_bytes().try_into() {
+                    keys.push(h);
+                }
+            }
+        }
+        Ok(keys)
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self.keys()?.len())
+    }
 }
 
 #[pyclass(frozen)]
 pub struct PyDB {
-    pydb: PyObject,
+    pub(crate) pydb: PyObject,
+    functions: FunctionMode,
 }
 
 #[pymethods]
 impl PyDB {
     #[new]
-    fn py_new(pydb: PyObject) -> Self {
-        Self { pydb }
+    #[pyo3(signature = (pydb, *, functions = FunctionMode::Name))]
+    fn py_new(pydb: PyObject, functions: FunctionMode) -> Self {
+        Self { pydb, functions }
     }
-    fn hash<'py>(&self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
-        serialize(obj, &mut self.pydb.bind(obj.py()))
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(
+            obj,
+            &mut self.pydb.bind(obj.py()),
+            functions.unwrap_or(self.functions),
+        )
     }
     fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
         deserialize(obj, &self.pydb.bind(obj.py()))
     }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let db = &self.pydb.bind(h.py());
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(Get::contains(db, h)?)
+    }
+    fn __delitem__(&self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(Put::delete(&mut self.pydb.bind(h.py()), extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.pydb.bind(py).keys()?)
+    }
+    fn __len__(&self, py: Python<'_>) -> PyResult<usize> {
+        Ok(Get::len(&self.pydb.bind(py))?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        let db = &mut self.pydb.bind(roots.py());
+        Ok(collect(db, &extract_roots(roots)?, dry_run)?)
+    }
 }
@@ -1,10 +1,15 @@
-use pyo3::{pyclass, pymethods, types::PyBytes, Bound, PyAny, PyResult};
+use pyo3::{
+    pyclass, pymethods,
+    types::{PyBytes, PyIterator},
+    Bound, PyAny, PyResult, Python,
+};
 
 use crate::{
     deserialize::deserialize,
-    mapping::{Get, Key, MappingError, MappingResult, Put},
+    gc::{collect, extract_roots, Report},
+    mapping::{content_equals, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put},
     nohash::NoHashBuilder,
-    serialize::serialize,
+    serialize::{serialize, FunctionMode},
 };
 
 use std::{
@@ -13,13 +18,16 @@ use std::{
 };
 
 #[pyclass(name = "RAM")]
-pub struct Ram(HashMap<Key, Vec<u8>, NoHashBuilder>);
+pub struct Ram {
+    blobs: HashMap<Key, Vec<u8>, NoHashBuilder>,
+    functions: FunctionMode,
+}
 
 impl Put for Ram {
     fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
-        match self.0.entry(h) {
+        match self.blobs.entry(h) {
             Entry::Occupied(e) => {
-                if e.get() != b.as_ref() {
+                if !content_equals(e.get().as_slice(), b.as_ref())? {
                     return Err(MappingError::Collision(h));
                 }
             }
@@ -29,26 +37,68 @@ impl Put for Ram {
         }
         Ok(())
     }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        self.blobs
+            .remove(&h)
+            .map_or_else(|| Err(MappingError::NotFound(h)), |_| Ok(()))
+    }
 }
 
 impl Get for Ram {
     fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
-        self.0
+        self.blobs
             .get(&h)
             .map_or_else(|| Err(MappingError::NotFound(h)), |v| Ok(v.deref()))
     }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Ok(self.blobs.contains_key(&h))
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        Ok(self.blobs.keys().copied().collect())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self.blobs.len())
+    }
 }
 
 #[pymethods]
 impl Ram {
     #[new]
-    fn py_new() -> Self {
-        Self(HashMap::default())
+    #[pyo3(signature = (*, functions = FunctionMode::Name))]
+    fn py_new(functions: FunctionMode) -> Self {
+        Self {
+            blobs: HashMap::default(),
+            functions,
+        }
     }
-    fn hash<'py>(&mut self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
-        serialize(obj, self)
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, self, functions.unwrap_or(self.functions))
     }
     fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
         deserialize(obj, self)
     }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        Ok(collect(self, &extract_roots(roots)?, dry_run)?)
+    }
 }
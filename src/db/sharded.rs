@@ -0,0 +1,281 @@
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyIterator, PyString},
+};
+
+use crate::{
+    deserialize::deserialize,
+    gc::{collect, extract_roots, Report},
+    mapping::{extract_key, iter_keys, Get, Key, MappingResult, Put},
+    serialize::{serialize, FunctionMode},
+};
+
+use super::{
+    filedb::FileDB,
+    fsdb::{write_new, FsDB},
+    sqlitedb::SqliteDB,
+    tiered::Tier,
+};
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+// The first line of a manifest file, which includes the version of its format.
+const MANIFEST_MAGIC: &str = "stash-sharded 1";
+
+// The manner in which keys are routed to shards: by the first 8 bytes of the key, read as a big
+// endian integer, modulo the number of shards.
+const LAYOUT: &str = "prefix64-mod";
+
+// A set of stores between which the blobs are divided by their hash.
+pub struct Shards<M> {
+    shards: Vec<M>,
+}
+
+impl<M> Shards<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    pub fn new(shards: Vec<M>) -> Self {
+        Self { shards }
+    }
+    fn shard(&self, h: &Key) -> &M {
+        let prefix = u64::from_be_bytes(h[..8].try_into().unwrap());
+        &self.shards[(prefix % self.shards.len() as u64) as usize]
+    }
+}
+
+impl Shards<Tier> {
+    // Whether a blob may be lost by the shard it is routed to.
+    pub(crate) fn may_evict(&self) -> bool {
+        self.shards.iter().any(Tier::may_evict)
+    }
+}
+
+impl<M> Put for Shards<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
+        Put::put(&mut self.shard(&h), h, b)
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        Put::delete(&mut self.shard(&h), h)
+    }
+}
+
+impl<M> Get for Shards<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
+        Ok(Get::get(&self.shard(&h), h)?.to_vec())
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Get::contains(&self.shard(&h), h)
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(Get::keys(&shard)?);
+        }
+        Ok(keys)
+    }
+    fn len(&self) -> MappingResult<usize> {
+        self.shards.iter().map(|shard| Get::len(&shard)).sum()
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        Get::size(&self.shard(&h), h)
+    }
+}
+
+// The class of the stores that hold the shards.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    FileDB,
+    FsDB,
+    SqliteDB,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::FileDB => "filedb",
+            Backend::FsDB => "fsdb",
+            Backend::SqliteDB => "sqlite",
+        }
+    }
+    fn parse(s: &str) -> Option<Self> {
+        [Backend::FileDB, Backend::FsDB, Backend::SqliteDB]
+            .into_iter()
+            .find(|backend| backend.name() == s)
+    }
+    fn open(self, py: Python<'_>, path: PathBuf, functions: FunctionMode) -> PyResult<Tier> {
+        Ok(match self {
+            Backend::FileDB => Tier::FileDB(Py::new(py, FileDB::new(path, functions)?)?),
+            Backend::FsDB => Tier::FsDB(Py::new(py, FsDB::new(path, None, None, functions)?)?),
+            Backend::SqliteDB => Tier::SqliteDB(Py::new(py, SqliteDB::new(path, functions)?)?),
+        })
+    }
+}
+
+impl FromPyObject<'_> for Backend {
+    fn extract_bound(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let s = obj.downcast::<PyString>()?.to_cow()?;
+        Backend::parse(&s).ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "invalid backend {:?}; expected 'filedb', 'fsdb' or 'sqlite'",
+                s
+            ))
+        })
+    }
+}
+
+// Parse the contents of a manifest file into the backend and the paths of the shards, or return
+// None if it is invalid. The manifest consists of the magic line, followed by lines with the
+// layout, the backend and the path of every shard.
+fn parse_manifest(s: &str) -> Option<(Backend, Vec<PathBuf>)> {
+    let mut lines = s.lines();
+    if lines.next()? != MANIFEST_MAGIC || lines.next()?.strip_prefix("layout ")? != LAYOUT {
+        return None;
+    }
+    let backend = Backend::parse(lines.next()?.strip_prefix("backend ")?)?;
+    let shards = lines
+        .map(|line| line.strip_prefix("shard ").map(PathBuf::from))
+        .collect::<Option<Vec<_>>>()?;
+    if shards.is_empty() {
+        return None;
+    }
+    Some((backend, shards))
+}
+
+fn format_manifest(backend: Backend, shards: &[PathBuf]) -> PyResult<String> {
+    let mut s = format!(
+        "{MANIFEST_MAGIC}\nlayout {LAYOUT}\nbackend {}\n",
+        backend.name()
+    );
+    for shard in shards {
+        match shard.to_str() {
+            Some(path) if !path.contains('\n') => s.push_str(&format!("shard {path}\n")),
+            _ => return Err(PyValueError::new_err("invalid shard path")),
+        }
+    }
+    Ok(s)
+}
+
+// Spread over several stores, each of which may be on another volume
+//
+// The backend and the paths of the shards are recorded in a manifest file when the store is
+// created, and read from it when the store is opened again, so that every blob is looked up in
+// the shard that it was stored in. The manifest is written as a whole, so that another process
+// never reads a partial one. It is an error for the requested shards or backend to differ
+// from the manifest. Relative shard paths are relative to the directory of the manifest.
+#[pyclass(name = "Sharded")]
+pub struct Sharded {
+    pub(crate) shards: Shards<Tier>,
+    functions: FunctionMode,
+}
+
+impl Sharded {
+    // * `path` - Path of the manifest file.
+    // * `shards` - Paths of the shards, or None for those of an existing manifest.
+    // * `backend` - Class of the shards, or None for that of an existing manifest or FileDB.
+    // * `functions` - How functions are hashed by default.
+    fn new(
+        py: Python<'_>,
+        path: PathBuf,
+        shards: Option<Vec<PathBuf>>,
+        backend: Option<Backend>,
+        functions: FunctionMode,
+    ) -> PyResult<Self> {
+        let manifest = match std::fs::read_to_string(&path) {
+            Ok(s) => parse_manifest(&s).ok_or_else(|| {
+                PyValueError::new_err(format!("invalid manifest file {}", path.display()))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let Some(shards) = shards.clone().filter(|shards| !shards.is_empty()) else {
+                    return Err(PyValueError::new_err("no shards given for a new store"));
+                };
+                let backend = backend.unwrap_or(Backend::FileDB);
+                let s = format_manifest(backend, &shards)?;
+                match write_new(&path, s.as_bytes()) {
+                    Ok(()) => {}
+                    // Another process created the store at the same time.
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        return Self::new(py, path, shards.into(), backend.into(), functions);
+                    }
+                    Err(e) => Err(e)?,
+                }
+                (backend, shards)
+            }
+            Err(e) => Err(e)?,
+        };
+        if shards.is_some_and(|shards| shards != manifest.1)
+            || backend.is_some_and(|backend| backend != manifest.0)
+        {
+            return Err(PyValueError::new_err(format!(
+                "store has backend {} and shards {:?}",
+                manifest.0.name(),
+                manifest.1
+            )));
+        }
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let shards = manifest
+            .1
+            .iter()
+            .map(|shard| manifest.0.open(py, dir.join(shard), functions))
+            .collect::<PyResult<_>>()?;
+        Ok(Self {
+            shards: Shards::new(shards),
+            functions,
+        })
+    }
+}
+
+#[pymethods]
+impl Sharded {
+    #[new]
+    #[pyo3(signature = (path, shards = None, *, backend = None, functions = FunctionMode::Name))]
+    fn py_new(
+        py: Python<'_>,
+        path: PathBuf,
+        shards: Option<Vec<PathBuf>>,
+        backend: Option<Backend>,
+        functions: FunctionMode,
+    ) -> PyResult<Self> {
+        Self::new(py, path, shards, backend, functions)
+    }
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, &mut self.shards, functions.unwrap_or(self.functions))
+    }
+    fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
+        deserialize(obj, &self.shards)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.shards.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.shards.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.shards.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.shards.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        Ok(collect(&mut self.shards, &extract_roots(roots)?, dry_run)?)
+    }
+}
@@ -0,0 +1,215 @@
+use pyo3::{
+    pyclass, pymethods,
+    types::{PyBytes, PyIterator},
+    Bound, PyAny, PyResult, Python,
+};
+
+use crate::{
+    deserialize::deserialize,
+    gc::{collect, extract_roots, Report},
+    mapping::{content_equals, extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put},
+    serialize::{serialize, FunctionMode},
+};
+
+use rusqlite::{Connection, OptionalExtension};
+
+use std::{
+    ops::Deref,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+// The blobs are stored in a single table of an SQLite database, keyed by their hash. The database
+// is opened in write-ahead logging mode, so that readers in other processes are not blocked by a
+// writer. All blobs of a hashed object are written in a single transaction.
+#[pyclass(name = "SqliteDB")]
+pub struct SqliteDB {
+    conn: Mutex<Connection>,
+    functions: FunctionMode,
+}
+
+impl From<rusqlite::Error> for MappingError {
+    fn from(err: rusqlite::Error) -> Self {
+        MappingError::Dyn(Box::new(err))
+    }
+}
+
+impl SqliteDB {
+    pub(crate) fn new(path: PathBuf, functions: FunctionMode) -> MappingResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS blobs (hash BLOB PRIMARY KEY, data BLOB NOT NULL)",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            functions,
+        })
+    }
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    // Run a function in a transaction, which is committed if the function succeeds and rolled
+    // back otherwise, including if the commit fails.
+    fn transaction<T, E: From<MappingError>>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.conn()
+            .execute_batch("BEGIN IMMEDIATE")
+            .map_err(MappingError::from)?;
+        let result = f(self);
+        if result.is_ok() {
+            commit(&self.conn()).map_err(MappingError::from)?;
+        } else {
+            self.conn()
+                .execute_batch("ROLLBACK")
+                .map_err(MappingError::from)?;
+        }
+        result
+    }
+}
+
+// Commit the current transaction, or roll it back if the commit fails, in which case the error of
+// the commit is returned.
+fn commit(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("COMMIT").inspect_err(|_| {
+        let _ = conn.execute_batch("ROLLBACK");
+    })
+}
+
+impl Put for SqliteDB {
+    fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
+        let conn = self.conn.get_mut().unwrap_or_else(PoisonError::into_inner);
+        // The insertion is ignored if the hash is already present, in which case the stored blob
+        // is verified instead.
+        let inserted = conn
+            .prepare_cached("INSERT OR IGNORE INTO blobs (hash, data) VALUES (?1, ?2)")?
+            .execute((h.as_slice(), b.as_ref()))?;
+        if inserted == 0 {
+            let stored: Vec<u8> = conn
+                .prepare_cached("SELECT data FROM blobs WHERE hash = ?1")?
+                .query_row([h.as_slice()], |row| row.get(0))?;
+            if !content_equals(stored.as_slice(), b.as_ref())? {
+                return Err(MappingError::Collision(h));
+            }
+        }
+        Ok(())
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        let deleted = self
+            .conn
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .prepare_cached("DELETE FROM blobs WHERE hash = ?1")?
+            .execute([h.as_slice()])?;
+        if deleted == 0 {
+            return Err(MappingError::NotFound(h));
+        }
+        Ok(())
+    }
+}
+
+impl Get for SqliteDB {
+    fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
+        self.conn()
+            .prepare_cached("SELECT data FROM blobs WHERE hash = ?1")?
+            .query_row([h.as_slice()], |row| row.get::<_, Vec<u8>>(0))
+            .optional()?
+            .ok_or(MappingError::NotFound(h))
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        Ok(self
+            .conn()
+            .prepare_cached("SELECT 1 FROM blobs WHERE hash = ?1")?
+            .exists([h.as_slice()])?)
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        // Rows with a hash of the wrong length, which can only be inserted by other tools, are
+        // skipped.
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached("SELECT hash FROM blobs")?;
+        let mut keys = Vec::new();
+        for h in stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))? {
+            if let Ok(h) = h?.try_into() {
+                keys.push(h);
+            }
+        }
+        Ok(keys)
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self
+            .conn()
+            .prepare_cached("SELECT COUNT(*) FROM blobs")?
+            .query_row([], |row| row.get(0))?)
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        self.conn()
+            .prepare_cached("SELECT length(data) FROM blobs WHERE hash = ?1")?
+            .query_row([h.as_slice()], |row| row.get(0))
+            .optional()?
+            .ok_or(MappingError::NotFound(h))
+    }
+}
+
+#[pymethods]
+impl SqliteDB {
+    #[new]
+    #[pyo3(signature = (path, *, functions = FunctionMode::Name))]
+    fn py_new(path: PathBuf, functions: FunctionMode) -> PyResult<Self> {
+        Ok(Self::new(path, functions)?)
+    }
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let functions = functions.unwrap_or(self.functions);
+        self.transaction(|db| serialize(obj, db, functions))
+    }
+    fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
+        deserialize(obj, self)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        let roots = extract_roots(roots)?;
+        self.transaction(|db| Ok(collect(db, &roots, dry_run)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_failed() {
+        let conn = Connection::open_in_memory().unwrap();
+        // A deferred foreign key constraint is only checked, and fails, on commit.
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE blobs (hash BLOB PRIMARY KEY);
+             CREATE TABLE refs (hash BLOB REFERENCES blobs (hash) DEFERRABLE INITIALLY DEFERRED);
+             BEGIN IMMEDIATE;
+             INSERT INTO refs VALUES (x'00');",
+        )
+        .unwrap();
+        assert!(commit(&conn).is_err());
+        assert!(conn.is_autocommit());
+    }
+}
@@ -0,0 +1,391 @@
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyIterator, PyString},
+};
+
+use crate::{
+    deserialize::deserialize,
+    gc::{collect, extract_roots, Report},
+    mapping::{extract_key, iter_keys, Get, Key, MappingError, MappingResult, Put},
+    nohash::NoHashBuilder,
+    serialize::{serialize, FunctionMode},
+};
+
+use super::{
+    cache::Cache, filedb::FileDB, fsdb::FsDB, overlay::Overlay, pydb::PyDB, ram::Ram,
+    sharded::Sharded, sqlitedb::SqliteDB,
+};
+
+use std::{collections::HashSet, ops::Deref};
+
+// The manner in which blobs are stored in the tiers below the top tier.
+#[derive(Clone, Copy)]
+pub enum WritePolicy {
+    // Store blobs in every tier immediately.
+    Through,
+    // Store blobs in the top tier, and in the other tiers when the store is flushed. The top tier
+    // must keep the blobs until then, so a `Cache`, or a store of tiers that may evict them like a
+    // `Cache`, is rejected.
+    Back,
+}
+
+impl FromPyObject<'_> for WritePolicy {
+    fn extract_bound(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        match obj.downcast::<PyString>()?.to_cow()?.as_ref() {
+            "write-through" => Ok(Self::Through),
+            "write-back" => Ok(Self::Back),
+            policy => Err(PyValueError::new_err(format!(
+                "invalid write policy {:?}; expected 'write-through' or 'write-back'",
+                policy
+            ))),
+        }
+    }
+}
+
+// A stack of stores, from the fastest at the top to the slowest at the bottom
+//
+// A blob is read from the highest tier that has it, and then promoted by storing it in the tiers
+// above. The tiers are shared references, such as Python objects, so that blobs can be promoted
+// while reading.
+pub struct Tiers<M> {
+    tiers: Vec<M>,
+    policy: WritePolicy,
+    dirty: HashSet<Key, NoHashBuilder>,
+}
+
+impl<M> Tiers<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    pub fn new(tiers: Vec<M>, policy: WritePolicy) -> Self {
+        Self {
+            tiers,
+            policy,
+            dirty: HashSet::default(),
+        }
+    }
+    // Store the blobs that were only stored in the top tier in all other tiers, and return their
+    // number. The blobs that could not be stored are flushed again the next time.
+    pub fn flush(&mut self) -> MappingResult<usize> {
+        let mut n = 0;
+        for h in self.dirty.iter().copied().collect::<Vec<_>>() {
+            let top = &self.tiers[0];
+            let b = Get::get(&top, h)?;
+            for tier in &self.tiers[1..] {
+                Put::put(&mut &*tier, h, &*b)?;
+            }
+            self.dirty.remove(&h);
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl Tiers<Tier> {
+    // Whether a blob may be lost: written through, only if every tier may lose it, and written
+    // back, if the top tier may lose it.
+    pub(crate) fn may_evict(&self) -> bool {
+        match self.policy {
+            WritePolicy::Through => self.tiers.iter().all(Tier::may_evict),
+            WritePolicy::Back => self.tiers.first().is_none_or(Tier::may_evict),
+        }
+    }
+}
+
+impl<M> Put for Tiers<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
+        match self.policy {
+            // The bottom tier is written first, so that a blob is never only in a faster tier.
+            WritePolicy::Through => {
+                for tier in self.tiers.iter().rev() {
+                    Put::put(&mut &*tier, h, b.as_ref())?;
+                }
+            }
+            WritePolicy::Back => {
+                if let Some(tier) = self.tiers.first() {
+                    Put::put(&mut &*tier, h, b.as_ref())?;
+                    if self.tiers.len() > 1 {
+                        self.dirty.insert(h);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        let mut found = false;
+        for tier in &self.tiers {
+            match Put::delete(&mut &*tier, h) {
+                Ok(()) => found = true,
+                Err(MappingError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.dirty.remove(&h);
+        if !found {
+            return Err(MappingError::NotFound(h));
+        }
+        Ok(())
+    }
+}
+
+impl<M> Get for Tiers<M>
+where
+    for<'a> &'a M: Put + Get,
+{
+    fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
+        for (i, tier) in self.tiers.iter().enumerate() {
+            match Get::get(&tier, h) {
+                Ok(b) => {
+                    let b = b.to_vec();
+                    for tier in &self.tiers[..i] {
+                        Put::put(&mut &*tier, h, &b)?;
+                    }
+                    return Ok(b);
+                }
+                Err(MappingError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(MappingError::NotFound(h))
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        for tier in &self.tiers {
+            if Get::contains(&tier, h)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        let mut keys = HashSet::<Key, NoHashBuilder>::default();
+        for tier in &self.tiers {
+            keys.extend(Get::keys(&tier)?);
+        }
+        Ok(keys.into_iter().collect())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        Ok(self.keys()?.len())
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        for tier in &self.tiers {
+            match Get::size(&tier, h) {
+                Err(MappingError::NotFound(_)) => {}
+                result => return result,
+            }
+        }
+        Err(MappingError::NotFound(h))
+    }
+}
+
+// A store that is held by Python, of any of the classes of this module or a mapping.
+pub enum Tier {
+    Ram(Py<Ram>),
+    Cache(Py<Cache>),
+    FsDB(Py<FsDB>),
+    FileDB(Py<FileDB>),
+    SqliteDB(Py<SqliteDB>),
+    Tiered(Py<Tiered>),
+    Overlay(Py<Overlay>),
+    Sharded(Py<Sharded>),
+    Mapping(PyObject),
+}
+
+impl FromPyObject<'_> for Tier {
+    fn extract_bound(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        Ok(if let Ok(db) = obj.downcast::<Ram>() {
+            Tier::Ram(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<Cache>() {
+            Tier::Cache(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<FsDB>() {
+            Tier::FsDB(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<FileDB>() {
+            Tier::FileDB(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<SqliteDB>() {
+            Tier::SqliteDB(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<Tiered>() {
+            Tier::Tiered(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<Overlay>() {
+            Tier::Overlay(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<Sharded>() {
+            Tier::Sharded(db.clone().unbind())
+        } else if let Ok(db) = obj.downcast::<PyDB>() {
+            Tier::Mapping(db.borrow().pydb.clone_ref(obj.py()))
+        } else {
+            Tier::Mapping(obj.clone().unbind())
+        })
+    }
+}
+
+// The operations of the Put and Get traits with concrete types, so that the stores of all tiers can
+// be called alike. The trait is kept out of scope so that its methods do not shadow those of the
+// Put and Get traits.
+mod store {
+    use crate::mapping::{Get, Key, MappingResult, Put};
+
+    pub trait Store {
+        fn put(&mut self, h: Key, b: &[u8]) -> MappingResult<()>;
+        fn delete(&mut self, h: Key) -> MappingResult<()>;
+        fn get(&self, h: Key) -> MappingResult<Vec<u8>>;
+        fn contains(&self, h: Key) -> MappingResult<bool>;
+        fn keys(&self) -> MappingResult<Vec<Key>>;
+        fn len(&self) -> MappingResult<usize>;
+        fn size(&self, h: Key) -> MappingResult<usize>;
+    }
+
+    // The blobs are copied out of the stores, as the tiers are only borrowed for the duration of a
+    // call.
+    impl<M: Put + Get> Store for M {
+        fn put(&mut self, h: Key, b: &[u8]) -> MappingResult<()> {
+            Put::put(self, h, b)
+        }
+        fn delete(&mut self, h: Key) -> MappingResult<()> {
+            Put::delete(self, h)
+        }
+        fn get(&self, h: Key) -> MappingResult<Vec<u8>> {
+            Ok(Get::get(self, h)?.to_vec())
+        }
+        fn contains(&self, h: Key) -> MappingResult<bool> {
+            Get::contains(self, h)
+        }
+        fn keys(&self) -> MappingResult<Vec<Key>> {
+            Get::keys(self)
+        }
+        fn len(&self) -> MappingResult<usize> {
+            Get::len(self)
+        }
+        fn size(&self, h: Key) -> MappingResult<usize> {
+            Get::size(self, h)
+        }
+    }
+}
+
+impl Tier {
+    // Call a function with the store of the tier, which is borrowed for the duration of the call.
+    fn with<T>(
+        &self,
+        f: impl FnOnce(&mut dyn store::Store) -> MappingResult<T>,
+    ) -> MappingResult<T> {
+        Python::with_gil(|py| match self {
+            Tier::Ram(db) => f(&mut *db.try_borrow_mut(py).map_err(PyErr::from)?),
+            Tier::Cache(db) => f(&mut *db.try_borrow_mut(py).map_err(PyErr::from)?),
+            Tier::FsDB(db) => f(&mut *db.try_borrow_mut(py).map_err(PyErr::from)?),
+            Tier::FileDB(db) => f(&mut *db.try_borrow_mut(py).map_err(PyErr::from)?),
+            Tier::SqliteDB(db) => f(&mut *db.try_borrow_mut(py).map_err(PyErr::from)?),
+            Tier::Tiered(db) => f(&mut db.try_borrow_mut(py).map_err(PyErr::from)?.tiers),
+            Tier::Overlay(db) => f(&mut db.try_borrow_mut(py).map_err(PyErr::from)?.layers),
+            Tier::Sharded(db) => f(&mut db.try_borrow_mut(py).map_err(PyErr::from)?.shards),
+            Tier::Mapping(obj) => f(&mut obj.bind(py)),
+        })
+    }
+    // Whether the store of the tier may lose blobs that were stored in it, as a `Cache` does.
+    pub(crate) fn may_evict(&self) -> bool {
+        Python::with_gil(|py| match self {
+            Tier::Cache(_) => true,
+            Tier::Tiered(db) => db.borrow(py).tiers.may_evict(),
+            Tier::Overlay(db) => db.borrow(py).layers.may_evict(),
+            Tier::Sharded(db) => db.borrow(py).shards.may_evict(),
+            _ => false,
+        })
+    }
+}
+
+impl Put for &Tier {
+    fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()> {
+        self.with(|db| db.put(h, b.as_ref()))
+    }
+    fn delete(&mut self, h: Key) -> MappingResult<()> {
+        self.with(|db| db.delete(h))
+    }
+}
+
+impl Get for &Tier {
+    fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>> {
+        self.with(|db| db.get(h))
+    }
+    fn contains(&self, h: Key) -> MappingResult<bool> {
+        self.with(|db| db.contains(h))
+    }
+    fn keys(&self) -> MappingResult<Vec<Key>> {
+        self.with(|db| db.keys())
+    }
+    fn len(&self) -> MappingResult<usize> {
+        self.with(|db| db.len())
+    }
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        self.with(|db| db.size(h))
+    }
+}
+
+#[pyclass(name = "Tiered")]
+pub struct Tiered {
+    tiers: Tiers<Tier>,
+    functions: FunctionMode,
+}
+
+// The blobs that were not written back yet are flushed when the store is dropped. As there is no
+// caller to return an error to, it is reported as unraisable.
+impl Drop for Tiered {
+    fn drop(&mut self) {
+        if let Err(e) = self.tiers.flush() {
+            Python::with_gil(|py| PyErr::from(e).write_unraisable(py, None));
+        }
+    }
+}
+
+#[pymethods]
+impl Tiered {
+    #[new]
+    #[pyo3(signature = (tiers, *, policy = WritePolicy::Through, functions = FunctionMode::Name))]
+    fn py_new(tiers: Vec<Tier>, policy: WritePolicy, functions: FunctionMode) -> PyResult<Self> {
+        if let (WritePolicy::Back, Some(tier)) = (policy, tiers.first()) {
+            if tier.may_evict() {
+                return Err(PyValueError::new_err(
+                    "the top tier of a write-back store cannot be a Cache, or a store that \
+                     contains one that may evict its blobs",
+                ));
+            }
+        }
+        Ok(Self {
+            tiers: Tiers::new(tiers, policy),
+            functions,
+        })
+    }
+    #[pyo3(signature = (obj, *, functions = None))]
+    fn hash<'py>(
+        &mut self,
+        obj: &Bound<'py, PyAny>,
+        functions: Option<FunctionMode>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        serialize(obj, &mut self.tiers, functions.unwrap_or(self.functions))
+    }
+    fn unhash<'py>(&self, obj: &'py Bound<'py, PyBytes>) -> PyResult<Bound<'py, PyAny>> {
+        deserialize(obj, &self.tiers)
+    }
+    fn flush(&mut self) -> PyResult<usize> {
+        Ok(self.tiers.flush()?)
+    }
+    fn __contains__(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(h) = extract_key(h) else {
+            return Ok(false);
+        };
+        Ok(self.tiers.contains(h)?)
+    }
+    fn __delitem__(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        Ok(self.tiers.delete(extract_key(h)?)?)
+    }
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        iter_keys(py, self.tiers.keys()?)
+    }
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.tiers.len()?)
+    }
+    #[pyo3(signature = (roots, *, dry_run = false))]
+    fn gc(&mut self, roots: &Bound<'_, PyAny>, dry_run: bool) -> PyResult<Report> {
+        Ok(collect(&mut self.tiers, &extract_roots(roots)?, dry_run)?)
+    }
+}
@@ -1,19 +1,273 @@
-use crate::{int::Int, mapping::Get, token};
+use crate::{
+    gc::chunks_offset,
+    int::Int,
+    mapping::{split_blob, Get, NBYTES},
+    qualname, token, varint,
+};
 use pyo3::{
-    exceptions::PyTypeError,
+    exceptions::{PyTypeError, PyValueError},
     intern,
     prelude::*,
     types::{
-        PyBool, PyByteArray, PyBytes, PyDict, PyFloat, PyFrozenSet, PyList, PySet, PyString,
-        PyTuple,
+        IntoPyDict, PyBool, PyByteArray, PyBytes, PyComplex, PyDict, PyFloat, PyFrozenSet, PyList,
+        PySet, PyString, PyTuple,
     },
 };
+use std::collections::HashMap;
 
 pub fn deserialize<'py, M: Get>(obj: &Bound<'py, PyBytes>, db: &M) -> PyResult<Bound<'py, PyAny>> {
     let b = db.get(obj.as_bytes().try_into().unwrap())?;
     let py = obj.py();
     let int = Int::new(py)?;
-    deserialize_chunk(&b, db, py, &int)
+    let (mut orderings, references, content) = split_blob(&b)?;
+    let memo = &mut Memo::new(references)?;
+    memo.levels.push(Level {
+        obj: None,
+        node: Some(0),
+        next: 0,
+    });
+    deserialize_chunk(content, db, py, &int, memo, &mut orderings)
+}
+
+// The state of a chunk that is being deserialized.
+struct Level<'py> {
+    // The object, once it is formed, if it can be referenced by the chunks it encloses.
+    obj: Option<Bound<'py, PyAny>>,
+    // The node of the position of the chunk, if any positions of shared objects lie within it.
+    node: Option<usize>,
+    // The index of the next chunk of the chunk.
+    next: usize,
+}
+
+// Structure to restore object references
+//
+// The chunks that are being deserialized are kept on a stack, for back references to the objects
+// that enclose them. The positions of the chunks of shared objects are read from the references
+// vector into a tree, whose edges are the index of a chunk within its parent. The object of the
+// first such chunk that is deserialized is used for all others.
+struct Memo<'py> {
+    levels: Vec<Level<'py>>,
+    edges: HashMap<(usize, usize), usize>,
+    groups: Vec<Option<usize>>,
+    objs: Vec<Option<Bound<'py, PyAny>>>,
+}
+
+impl<'py> Memo<'py> {
+    fn new(mut references: &[u8]) -> PyResult<Self> {
+        let mut memo = Self {
+            levels: Vec::new(),
+            edges: HashMap::new(),
+            groups: vec![None],
+            objs: Vec::new(),
+        };
+        while !references.is_empty() {
+            let index = varint::read(&mut references)?;
+            let mut node = 0;
+            for _ in 0..varint::read(&mut references)? {
+                let next = memo.groups.len();
+                node = *memo
+                    .edges
+                    .entry((node, varint::read(&mut references)?))
+                    .or_insert(next);
+                if node == next {
+                    memo.groups.push(None);
+                }
+            }
+            if index > memo.objs.len() {
+                return Err(PyValueError::new_err("invalid reference"));
+            } else if index == memo.objs.len() {
+                memo.objs.push(None);
+            }
+            memo.groups[node] = Some(index);
+        }
+        Ok(memo)
+    }
+    // Continue with the chunk at the given index within the current chunk.
+    fn seek(&mut self, index: usize) {
+        self.levels.last_mut().unwrap().next = index;
+    }
+    // Record the object of the current chunk, once it is formed, for back references to it.
+    fn form(&mut self, obj: &Bound<'py, PyAny>) {
+        self.levels.last_mut().unwrap().obj = Some(obj.clone());
+    }
+}
+
+// Split a byte stream into groups of N chunks in their original order
+//
+// This routine takes the serialized items of a set or dictionary and reads the next entry from the
+// orderings vector to undo the sorting of the serialization, if applicable. Every group is
+// returned along with its index in the byte stream.
+fn ordered_chunks<'a, const N: usize>(
+    mut data: &'a [u8],
+    orderings: &mut &[u8],
+) -> PyResult<Vec<(usize, &'a [u8])>> {
+    let mut groups = Vec::new();
+    while !data.is_empty() {
+        let mut i = 0;
+        for _ in 0..N {
+            let n = data[i];
+            i += 1 + if n == 0 { NBYTES } else { n as usize };
+        }
+        let group;
+        (group, data) = data.split_at(i);
+        groups.push(group);
+    }
+    let n = varint::read(&mut *orderings)?;
+    if n == 0 {
+        return Ok(groups.into_iter().enumerate().collect());
+    }
+    if n != groups.len() {
+        return Err(PyValueError::new_err("invalid ordering"));
+    }
+    (0..n)
+        .map(|_| {
+            let i = varint::read(&mut *orderings)?;
+            match groups.get(i) {
+                Some(group) => Ok((i, *group)),
+                None => Err(PyValueError::new_err("invalid ordering")),
+            }
+        })
+        .collect()
+}
+
+// Advance the orderings vector past the orderings of an inline chunk that is not deserialized.
+fn skip_orderings(b: &[u8], orderings: &mut &[u8]) -> PyResult<()> {
+    let (token, data) = b.split_at(1);
+    let Some(offset) = chunks_offset(token[0])? else {
+        return Ok(());
+    };
+    let data = data
+        .get(offset..)
+        .ok_or_else(|| PyValueError::new_err("truncated data"))?;
+    let chunks = match token[0] {
+        token::SET | token::FROZENSET => ordered_chunks::<1>(data, orderings)?,
+        token::DICT => ordered_chunks::<2>(data, orderings)?,
+        _ => vec![(0, data)],
+    };
+    for (_, mut data) in chunks {
+        while let Some((&n, rest)) = data.split_first() {
+            let n = n as usize;
+            let chunk = rest
+                .get(..if n == 0 { NBYTES } else { n })
+                .ok_or_else(|| PyValueError::new_err("truncated data"))?;
+            if n != 0 {
+                skip_orderings(chunk, orderings)?;
+            }
+            data = &rest[chunk.len()..];
+        }
+    }
+    Ok(())
+}
+
+// Split a fixed number of bytes off the start of a byte stream
+fn split_fields<const N: usize>(data: &mut &[u8]) -> PyResult<[u8; N]> {
+    let Some((fields, rest)) = data.split_at_checked(N) else {
+        return Err(PyValueError::new_err("truncated data"));
+    };
+    *data = rest;
+    Ok(fields.try_into()?)
+}
+
+// Apply the state of a reduced object
+//
+// This routine follows pickle's semantics: the state is passed to `__setstate__` if the object
+// defines it, or is otherwise expected to be a dictionary of instance attributes that is merged
+// into the object's `__dict__`, or a `(dict, slotstate)` tuple where either item may be None and
+// the slot state is a dictionary of attributes that are set via `setattr`.
+fn set_state<'py>(obj: &Bound<'py, PyAny>, state: Bound<'py, PyAny>) -> PyResult<()> {
+    let py = obj.py();
+    if let Ok(setstate) = obj.getattr(intern!(py, "__setstate__")) {
+        setstate.call1((state,))?;
+        return Ok(());
+    }
+    let (state, slotstate) = match state.downcast_exact::<PyTuple>() {
+        Ok(t) if t.len() == 2 => (t.get_item(0)?, Some(t.get_item(1)?)),
+        _ => (state, None),
+    };
+    if state.is_truthy()? {
+        let Ok(items) = state.downcast::<PyDict>() else {
+            return Err(PyTypeError::new_err(format!(
+                "cannot apply state of type {} to {} object: state is not a dictionary",
+                state.get_type().name()?,
+                obj.get_type().name()?,
+            )));
+        };
+        let dict = obj.getattr(intern!(py, "__dict__"))?;
+        for (k, v) in items {
+            if let Ok(s) = k.downcast_exact::<PyString>() {
+                dict.set_item(PyString::intern(py, &s.to_cow()?), v)?;
+            } else {
+                dict.set_item(k, v)?;
+            }
+        }
+    }
+    if let Some(slotstate) = slotstate.filter(|slotstate| !slotstate.is_none()) {
+        let Ok(items) = slotstate.downcast::<PyDict>() else {
+            return Err(PyTypeError::new_err(format!(
+                "cannot apply slot state of type {} to {} object: slot state is not a dictionary",
+                slotstate.get_type().name()?,
+                obj.get_type().name()?,
+            )));
+        };
+        for (k, v) in items {
+            obj.setattr(k.downcast_into::<PyString>()?, v)?;
+        }
+    }
+    Ok(())
+}
+
+// Deserialize the next chunk of a byte stream
+//
+// This routine reads the length byte at the start of `data`, deserializes the subsequent inline
+// or hashed chunk, and advances `data` past it. A hashed chunk brings its own orderings vector.
+// A chunk of a shared object that was deserialized before is skipped, along with its orderings.
+fn deserialize_next<'py, M: Get>(
+    data: &mut &[u8],
+    db: &M,
+    py: Python<'py>,
+    int: &Int<'py>,
+    memo: &mut Memo<'py>,
+    orderings: &mut &[u8],
+) -> PyResult<Bound<'py, PyAny>> {
+    let parent = memo.levels.last_mut().unwrap();
+    let index = parent.next;
+    parent.next += 1;
+    let node = parent
+        .node
+        .and_then(|node| memo.edges.get(&(node, index)).copied());
+    let shared = node.and_then(|node| memo.groups[node]);
+    if let Some(obj) = shared.and_then(|index| memo.objs[index].clone()) {
+        if data[0] == 0 {
+            *data = data
+                .get(1 + NBYTES..)
+                .ok_or_else(|| PyValueError::new_err("truncated data"))?;
+        } else {
+            let (chunk, rest) = data[1..].split_at(data[0] as usize);
+            *data = rest;
+            skip_orderings(chunk, orderings)?;
+        }
+        return Ok(obj);
+    }
+    memo.levels.push(Level {
+        obj: None,
+        node,
+        next: 0,
+    });
+    let obj = if data[0] == 0 {
+        let (owned, rest) = db.get_blob(&data[1..])?;
+        *data = rest;
+        let (mut orderings, _, content) = split_blob(&owned)?;
+        deserialize_chunk(content, db, py, int, memo, &mut orderings)
+    } else {
+        let (chunk, rest) = data[1..].split_at(data[0] as usize);
+        *data = rest;
+        deserialize_chunk(chunk, db, py, int, memo, orderings)
+    }?;
+    let _ = memo.levels.pop();
+    if let Some(index) = shared {
+        memo.objs[index] = Some(obj.clone());
+    }
+    Ok(obj)
 }
 
 // Deserialize a Python object from a byte stream
@@ -25,126 +279,71 @@ pub fn deserialize<'py, M: Get>(obj: &Bound<'py, PyBytes>, db: &M) -> PyResult<B
 // * `db` - Database to load hashed blobs from.
 // * `py` - A marker token that represents holding the GIL.
 // * `int` - Helper object to facilitate deserialization of integers.
+// * `memo` - Structure to restore object references.
+// * `orderings` - Remainder of the orderings vector of the blob that `b` is part of.
 fn deserialize_chunk<'py, M: Get>(
     b: &[u8],
     db: &M,
     py: Python<'py>,
     int: &Int<'py>,
+    memo: &mut Memo<'py>,
+    orderings: &mut &[u8],
 ) -> PyResult<Bound<'py, PyAny>> {
     let (token, mut data) = b.split_at(1);
 
-    let mut owned;
+    // An object that can be referenced by the chunks it encloses is recorded as soon as it is
+    // formed, which for mutable objects is before their children are deserialized so that cyclic
+    // references can be restored.
+
     let obj = match token[0] {
         token::BYTES => PyBytes::new(py, data).into_any(),
-        token::BYTEARRAY => PyByteArray::new(py, data).into_any(),
+        token::BYTEARRAY => {
+            let obj = PyByteArray::new(py, data).into_any();
+            memo.form(&obj);
+            obj
+        }
         token::STRING => PyString::new(py, std::str::from_utf8(data)?).into_any(),
         token::INT => int.read_from(data)?.into_any(),
         token::FLOAT => PyFloat::new(py, f64::from_le_bytes(data.try_into()?)).into_any(),
         token::LIST => {
             let obj = PyList::empty(py);
+            memo.form(obj.as_any());
             while !data.is_empty() {
-                obj.append(deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?)?;
+                obj.append(deserialize_next(&mut data, db, py, int, memo, orderings)?)?;
             }
             obj.into_any()
         }
         token::TUPLE => {
             let mut objs = Vec::new();
             while !data.is_empty() {
-                objs.push(deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?);
+                objs.push(deserialize_next(&mut data, db, py, int, memo, orderings)?);
             }
             PyTuple::new(py, objs)?.into_any()
         }
         token::SET => {
             let obj = PySet::empty(py)?;
-            while !data.is_empty() {
-                obj.add(deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?)?;
+            memo.form(obj.as_any());
+            for (i, mut chunk) in ordered_chunks::<1>(data, orderings)? {
+                memo.seek(i);
+                obj.add(deserialize_next(&mut chunk, db, py, int, memo, orderings)?)?;
             }
             obj.into_any()
         }
         token::FROZENSET => {
             let mut objs = Vec::new();
-            while !data.is_empty() {
-                objs.push(deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?);
+            for (i, mut chunk) in ordered_chunks::<1>(data, orderings)? {
+                memo.seek(i);
+                objs.push(deserialize_next(&mut chunk, db, py, int, memo, orderings)?);
             }
             PyFrozenSet::new(py, objs)?.into_any()
         }
         token::DICT => {
             let d = PyDict::new(py);
-            while !data.is_empty() {
-                let k = deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?;
-                let v = deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?;
+            memo.form(d.as_any());
+            for (i, mut chunks) in ordered_chunks::<2>(data, orderings)? {
+                memo.seek(2 * i);
+                let k = deserialize_next(&mut chunks, db, py, int, memo, orderings)?;
+                let v = deserialize_next(&mut chunks, db, py, int, memo, orderings)?;
                 d.set_item(k, v)?;
             }
             d.into_any()
@@ -153,49 +352,245 @@ fn deserialize_chunk<'py, M: Get>(
         token::TRUE => PyBool::new(py, true).to_owned().into_any(),
         token::FALSE => PyBool::new(py, false).to_owned().into_any(),
         token::GLOBAL => {
-            let (module, qualname) = std::str::from_utf8(data)?
+            let (module, name) = std::str::from_utf8(data)?
                 .split_once(':')
                 .expect("qualname does not contain a colon");
-            PyModule::import(py, module)?.getattr(qualname)?.into_any()
+            qualname::resolve(PyModule::import(py, module)?.into_any(), name)?
         }
         token::REDUCE => {
-            let mut objs = Vec::new();
+            // The reduction tuple consists of a callable, its arguments, and optionally the
+            // object's state, list items, dictionary items and state setter. As in pickle, the
+            // object is formed before the remaining items are deserialized so that these can refer
+            // back to it, and the state is applied last.
+            let func = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            let args = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            let obj = func.call1(args.downcast_exact::<PyTuple>()?)?;
+            memo.form(&obj);
+            let mut items = Vec::new();
             while !data.is_empty() {
-                objs.push(deserialize_chunk(
-                    if data[0] == 0 {
-                        (owned, data) = db.get_blob(&data[1..])?;
-                        owned.as_ref()
-                    } else {
-                        let chunk;
-                        (chunk, data) = data[1..].split_at(data[0] as usize);
-                        chunk
-                    },
-                    db,
-                    py,
-                    int,
-                )?);
-            }
-            let mut it = objs.into_iter();
-            let func = it
-                .next()
-                .expect("reduction tuple does not contain function");
-            let obj = func.call1(
-                it.next()
-                    .expect("reduction tuple does not contain arguments")
-                    .downcast_exact()?,
-            )?;
-            if let Some(state) = it.next() {
-                if let Ok(setstate) = obj.getattr(intern!(py, "__setstate__")) {
-                    setstate.call1((state,))?;
-                } else if let Ok(items) = state.downcast_exact::<PyDict>() {
-                    for (k, v) in items {
-                        obj.setattr(k.downcast_exact::<PyString>()?, v)?;
+                items.push(deserialize_next(&mut data, db, py, int, memo, orderings)?);
+            }
+            let mut items = items
+                .into_iter()
+                .map(|item| Some(item).filter(|item| !item.is_none()));
+            let state = items.next().flatten();
+            if let Some(listitems) = items.next().flatten() {
+                if let Ok(extend) = obj.getattr(intern!(py, "extend")) {
+                    extend.call1((listitems,))?;
+                } else {
+                    for item in listitems.try_iter()? {
+                        obj.call_method1(intern!(py, "append"), (item?,))?;
                     }
                 }
             }
-            // TODO else errors
+            if let Some(dictitems) = items.next().flatten() {
+                for item in dictitems.try_iter()? {
+                    let (key, value): (Bound<PyAny>, Bound<PyAny>) = item?.extract()?;
+                    obj.set_item(key, value)?;
+                }
+            }
+            if let Some(state) = state {
+                if let Some(setter) = items.next().flatten() {
+                    setter.call1((&obj, state))?;
+                } else {
+                    set_state(&obj, state)?;
+                }
+            }
+            obj
+        }
+        token::CODE => {
+            // A code object is formed by replacing the fields of a template code object, which
+            // supports fields that are specific to the running Python version.
+            let fields = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            PyModule::import(py, "builtins")?
+                .getattr(intern!(py, "compile"))?
+                .call1(("", "<stash>", "exec"))?
+                .call_method(
+                    intern!(py, "replace"),
+                    (),
+                    Some(fields.downcast_exact::<PyDict>()?),
+                )?
+        }
+        token::FUNCTION => {
+            // The function is formed with empty closure cells, which are filled after the function
+            // is formed so that closures can refer back to the function itself.
+            let module = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            let code = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            let defaults = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            let kwdefaults = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            let types = PyModule::import(py, "types")?;
+            let globals = if module.is_none() {
+                PyDict::new(py)
+            } else {
+                PyModule::import(py, module.downcast_exact::<PyString>()?.to_cow()?)?.dict()
+            };
+            let cell_type = types.getattr(intern!(py, "CellType"))?;
+            let cells = code
+                .getattr(intern!(py, "co_freevars"))?
+                .try_iter()?
+                .map(|_| cell_type.call0())
+                .collect::<PyResult<Vec<_>>>()?;
+            let closure = if cells.is_empty() {
+                py.None().into_bound(py)
+            } else {
+                PyTuple::new(py, &cells)?.into_any()
+            };
+            let obj = types.getattr(intern!(py, "FunctionType"))?.call1((
+                &code,
+                &globals,
+                py.None(),
+                defaults,
+                closure,
+            ))?;
+            obj.setattr(intern!(py, "__kwdefaults__"), kwdefaults)?;
+            if let Ok(qualname) = code.getattr(intern!(py, "co_qualname")) {
+                obj.setattr(intern!(py, "__qualname__"), qualname)?;
+            }
+            memo.form(&obj);
+            let contents = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+            if !contents.is_none() {
+                for (cell, value) in cells.iter().zip(contents.try_iter()?) {
+                    cell.setattr(intern!(py, "cell_contents"), value?)?;
+                }
+            }
+            // The referenced global objects of a function that is serialized by content serve
+            // to form the hash; the function itself uses the globals of its module, unless it
+            // does not have one.
+            if !data.is_empty() {
+                let referenced = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+                if module.is_none() {
+                    globals.update(referenced.downcast_exact::<PyDict>()?.as_mapping())?;
+                }
+            }
+            obj
+        }
+        token::COMPLEX => {
+            let [re, im]: [[u8; 8]; 2] = [split_fields(&mut data)?, split_fields(&mut data)?];
+            PyComplex::from_doubles(py, f64::from_le_bytes(re), f64::from_le_bytes(im)).into_any()
+        }
+        token::RANGE | token::SLICE | token::FRACTION | token::TIMEZONE | token::ENUM => {
+            // These values are formed by calling their type with the deserialized items, except
+            // for enum members, for which the type is the first item.
+            let mut items = Vec::new();
+            while !data.is_empty() {
+                items.push(deserialize_next(&mut data, db, py, int, memo, orderings)?);
+            }
+            let func = match token[0] {
+                token::RANGE => PyModule::import(py, "builtins")?.getattr("range")?,
+                token::SLICE => PyModule::import(py, "builtins")?.getattr("slice")?,
+                token::FRACTION => PyModule::import(py, "fractions")?.getattr("Fraction")?,
+                token::TIMEZONE => PyModule::import(py, "datetime")?.getattr("timezone")?,
+                _ if items.is_empty() => return Err(PyValueError::new_err("truncated data")),
+                _ => items.remove(0),
+            };
+            func.call1(PyTuple::new(py, items)?)?
+        }
+        token::ELLIPSIS => py.Ellipsis().into_bound(py),
+        token::DECIMAL => PyModule::import(py, "decimal")?
+            .getattr("Decimal")?
+            .call1((std::str::from_utf8(data)?,))?,
+        token::UUID => PyModule::import(py, "uuid")?.getattr("UUID")?.call(
+            (),
+            Some(&[("bytes", PyBytes::new(py, data))].into_py_dict(py)?),
+        )?,
+        token::DATE | token::TIME | token::DATETIME => {
+            // Dates and times are formed from keyword arguments, as times lack the date fields.
+            let kwargs = PyDict::new(py);
+            if token[0] != token::TIME {
+                let [y0, y1, month, day] = split_fields(&mut data)?;
+                kwargs.set_item("year", u16::from_be_bytes([y0, y1]))?;
+                kwargs.set_item("month", month)?;
+                kwargs.set_item("day", day)?;
+            }
+            if token[0] != token::DATE {
+                let [hour, minute, second, us0, us1, us2, fold] = split_fields(&mut data)?;
+                kwargs.set_item("hour", hour)?;
+                kwargs.set_item("minute", minute)?;
+                kwargs.set_item("second", second)?;
+                kwargs.set_item("microsecond", u32::from_be_bytes([0, us0, us1, us2]))?;
+                kwargs.set_item("fold", fold)?;
+                if !data.is_empty() {
+                    let tzinfo = deserialize_next(&mut data, db, py, int, memo, orderings)?;
+                    kwargs.set_item("tzinfo", tzinfo)?;
+                }
+            }
+            let name = match token[0] {
+                token::DATE => "date",
+                token::TIME => "time",
+                _ => "datetime",
+            };
+            PyModule::import(py, "datetime")?
+                .getattr(name)?
+                .call((), Some(&kwargs))?
+        }
+        token::TIMEDELTA => {
+            let days = i32::from_be_bytes(split_fields(&mut data)?);
+            let [s0, s1, s2, us0, us1, us2] = split_fields(&mut data)?;
+            PyModule::import(py, "datetime")?
+                .getattr("timedelta")?
+                .call1((
+                    days,
+                    u32::from_be_bytes([0, s0, s1, s2]),
+                    u32::from_be_bytes([0, us0, us1, us2]),
+                ))?
+        }
+        token::PATH => {
+            let [flavour] = split_fields(&mut data)?;
+            let Some(name) = token::PATH_TYPES.get(flavour as usize) else {
+                return Err(PyValueError::new_err("invalid path flavour"));
+            };
+            PyModule::import(py, "pathlib")?
+                .getattr(*name)?
+                .call1((std::str::from_utf8(data)?,))?
+        }
+        token::MEMORYVIEW | token::ARRAY | token::NDARRAY => {
+            // Buffers are formed from a copy of their data in C order, which is made writable
+            // unless the original object was a read-only view.
+            let mut items = Vec::new();
+            while !data.is_empty() {
+                items.push(deserialize_next(&mut data, db, py, int, memo, orderings)?);
+            }
+            let Some(buffer) = items.pop() else {
+                return Err(PyValueError::new_err("truncated data"));
+            };
+            let obj = match (token[0], items.as_slice()) {
+                (token::MEMORYVIEW, [format, shape, readonly]) => {
+                    let buffer = if readonly.is_truthy()? {
+                        buffer
+                    } else {
+                        PyByteArray::from(&buffer)?.into_any()
+                    };
+                    PyModule::import(py, "builtins")?
+                        .getattr("memoryview")?
+                        .call1((buffer,))?
+                        .call_method1(intern!(py, "cast"), (format, shape))?
+                }
+                (token::ARRAY, [typecode]) => PyModule::import(py, "array")?
+                    .getattr("array")?
+                    .call1((typecode, buffer))?,
+                (token::NDARRAY, [dtype, shape]) => PyModule::import(py, "numpy")?
+                    .getattr("frombuffer")?
+                    .call1((PyByteArray::from(&buffer)?, dtype))?
+                    .call_method1(intern!(py, "reshape"), (shape,))?,
+                _ => return Err(PyValueError::new_err("invalid buffer")),
+            };
+            memo.form(&obj);
             obj
         }
+        token::BACKREF => {
+            // The back reference counts the levels between the chunk and the enclosing object.
+            let distance = data.iter().fold(0, |index, b| (index << 8) | *b as usize);
+            let level = memo.levels.len() - 1;
+            match level.checked_sub(distance + 1).map(|i| &memo.levels[i].obj) {
+                Some(Some(obj)) => obj.clone(),
+                Some(None) => {
+                    return Err(PyValueError::new_err(
+                        "cannot restore reference to object that is still being formed",
+                    ))
+                }
+                None => return Err(PyValueError::new_err("invalid back reference")),
+            }
+        }
         _ => return Err(PyTypeError::new_err("cannot load object")),
     };
 
@@ -0,0 +1,153 @@
+use crate::{
+    mapping::{extract_key, split_blob, Get, Key, MappingResult, Put, NBYTES},
+    nohash::NoHashBuilder,
+    token,
+};
+use pyo3::prelude::*;
+use std::{
+    collections::HashSet,
+    io::{Error, ErrorKind, Result as IoResult},
+};
+
+// The outcome of a garbage collection: the number of reachable blobs, and the number and total
+// size of the unreachable blobs that were removed, or would be removed if this is a dry run.
+#[pyclass(frozen, get_all, name = "GCReport")]
+pub struct Report {
+    live: usize,
+    garbage: usize,
+    reclaimable: usize,
+    dry_run: bool,
+}
+
+#[pymethods]
+impl Report {
+    fn __repr__(&self) -> String {
+        format!(
+            "GCReport(live={}, garbage={}, reclaimable={}, dry_run={})",
+            self.live,
+            self.garbage,
+            self.reclaimable,
+            if self.dry_run { "True" } else { "False" },
+        )
+    }
+}
+
+// Extract root hashes from an iterable of bytes objects.
+pub fn extract_roots(roots: &Bound<PyAny>) -> PyResult<Vec<Key>> {
+    roots.try_iter()?.map(|h| extract_key(&h?)).collect()
+}
+
+// Remove all blobs that are not reachable from the root hashes
+//
+// This routine marks the blobs that are reachable from the roots by parsing their content for
+// hashed chunks, and then sweeps the database for blobs that were not marked. If any of the
+// reachable blobs is missing or cannot be parsed, the collection is aborted before anything is
+// removed.
+//
+// * `db` - Database to collect garbage from.
+// * `roots` - Hashes of the objects to keep.
+// * `dry_run` - Report the unreachable blobs without removing them.
+pub fn collect<M: Put + Get>(db: &mut M, roots: &[Key], dry_run: bool) -> MappingResult<Report> {
+    let keys = db.keys()?;
+    collect_from(db, keys, roots, dry_run)
+}
+
+// Remove the blobs with the given hashes that are not reachable from the root hashes, for
+// databases in which only some of the blobs can be removed.
+pub fn collect_from<M: Put + Get>(
+    db: &mut M,
+    keys: Vec<Key>,
+    roots: &[Key],
+    dry_run: bool,
+) -> MappingResult<Report> {
+    let mut marked = HashSet::<Key, NoHashBuilder>::default();
+    let mut stack = roots.to_vec();
+    while let Some(h) = stack.pop() {
+        if marked.insert(h) {
+            let b = db.get(h)?;
+            let (_, _, content) = split_blob(&b)?;
+            mark_content(content, &mut stack)?;
+        }
+    }
+    let mut report = Report {
+        live: marked.len(),
+        garbage: 0,
+        reclaimable: 0,
+        dry_run,
+    };
+    for h in keys {
+        if !marked.contains(&h) {
+            report.garbage += 1;
+            report.reclaimable += db.size(h)?;
+            if !dry_run {
+                db.delete(h)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+// Return the number of bytes of raw data that precede the chunks of an object, or None if the
+// object's data does not contain chunks at all.
+pub(crate) fn chunks_offset(token: u8) -> IoResult<Option<usize>> {
+    match token {
+        token::LIST
+        | token::TUPLE
+        | token::SET
+        | token::FROZENSET
+        | token::DICT
+        | token::REDUCE
+        | token::FUNCTION
+        | token::CODE
+        | token::RANGE
+        | token::SLICE
+        | token::FRACTION
+        | token::TIMEZONE
+        | token::ENUM
+        | token::MEMORYVIEW
+        | token::ARRAY
+        | token::NDARRAY => Ok(Some(0)),
+        token::TIME => Ok(Some(7)),
+        token::DATETIME => Ok(Some(11)),
+        token::INT
+        | token::BYTES
+        | token::STRING
+        | token::FLOAT
+        | token::NONE
+        | token::TRUE
+        | token::FALSE
+        | token::BYTEARRAY
+        | token::GLOBAL
+        | token::BACKREF
+        | token::COMPLEX
+        | token::ELLIPSIS
+        | token::DECIMAL
+        | token::UUID
+        | token::DATE
+        | token::TIMEDELTA
+        | token::PATH => Ok(None),
+        _ => Err(Error::new(ErrorKind::InvalidData, "invalid token")),
+    }
+}
+
+// Add the hashes of the hashed chunks in the serialization of an object to the stack, and
+// recurse into its inline chunks.
+fn mark_content(b: &[u8], stack: &mut Vec<Key>) -> IoResult<()> {
+    let truncated = || Error::from(ErrorKind::UnexpectedEof);
+    let (token, mut data) = b.split_first().ok_or_else(truncated)?;
+    let Some(offset) = chunks_offset(*token)? else {
+        return Ok(());
+    };
+    data = data.get(offset..).ok_or_else(truncated)?;
+    while let Some((n, rest)) = data.split_first() {
+        let len = if *n == 0 { NBYTES } else { *n as usize };
+        let chunk = rest.get(..len).ok_or_else(truncated)?;
+        if *n == 0 {
+            stack.push(chunk.try_into().unwrap());
+        } else {
+            mark_content(chunk, stack)?;
+        }
+        data = &rest[len..];
+    }
+    Ok(())
+}
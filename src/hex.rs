@@ -11,6 +11,17 @@ impl Display for Hex<'_> {
     }
 }
 
+// Parse a string of hexadecimal digits, or return None if it is not one.
+pub fn parse(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -20,4 +31,11 @@ mod tests {
         let h = &[1, 2, 3];
         assert_eq!(format!("{}", Hex(h)), "010203")
     }
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("01ff0a"), Some(vec![1, 255, 10]));
+        assert_eq!(parse("01f"), None);
+        assert_eq!(parse("0g"), None);
+        assert_eq!(parse("+1"), None);
+    }
 }
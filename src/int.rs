@@ -28,6 +28,16 @@ impl<'py> Int<'py> {
         })
     }
     pub fn write_to(&self, b: &mut Vec<u8>, obj: &Bound<'py, PyAny>) -> PyResult<()> {
+        // Integers that fit in 64 or 128 bits are encoded without calling into Python; only
+        // larger integers go through `int.to_bytes`.
+        if let Ok(n) = obj.extract::<i64>() {
+            write_i128(b, n as i128);
+            return Ok(());
+        }
+        if let Ok(n) = obj.extract::<i128>() {
+            write_i128(b, n);
+            return Ok(());
+        }
         let neg = obj.lt(0)?;
         let n: usize = (if !neg {
             self.bit_length.call1((obj,))?
@@ -42,6 +52,75 @@ impl<'py> Int<'py> {
         Ok(())
     }
     pub fn read_from(&self, b: &[u8]) -> PyResult<Bound<'py, PyAny>> {
-        self.from_bytes.call((b,), Some(&self.kwargs))
+        let py = self.kwargs.py();
+        match read_i128(b) {
+            Some(n) => Ok(if let Ok(n) = i64::try_from(n) {
+                n.into_pyobject(py)?.into_any()
+            } else {
+                n.into_pyobject(py)?.into_any()
+            }),
+            None => self.from_bytes.call((b,), Some(&self.kwargs)),
+        }
+    }
+}
+
+// Append the minimal big-endian two's complement representation of an integer, which is empty for
+// zero, in agreement with the encoding of larger integers by `int.to_bytes`.
+fn write_i128(b: &mut Vec<u8>, n: i128) {
+    let bits = 128 - if n < 0 { !n } else { n }.leading_zeros() as usize;
+    if n < 0 || bits > 0 {
+        b.extend_from_slice(&n.to_be_bytes()[15 - bits / 8..]);
+    }
+}
+
+// Read a big-endian two's complement integer, or return None if it does not fit in 128 bits.
+fn read_i128(b: &[u8]) -> Option<i128> {
+    if b.len() > 16 {
+        return None;
+    }
+    let mut bytes = [if b.first().is_some_and(|b| *b >= 0x80) {
+        0xff
+    } else {
+        0
+    }; 16];
+    bytes[16 - b.len()..].copy_from_slice(b);
+    Some(i128::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        for (n, expected) in [
+            (0, &[][..]),
+            (1, &[0x01]),
+            (-1, &[0xff]),
+            (127, &[0x7f]),
+            (128, &[0x00, 0x80]),
+            (-128, &[0x80]),
+            (-129, &[0xff, 0x7f]),
+        ] {
+            let mut b = Vec::new();
+            write_i128(&mut b, n);
+            assert_eq!(b, expected);
+        }
+    }
+    #[test]
+    fn test_write_extremes() {
+        let mut b = Vec::new();
+        write_i128(&mut b, i128::MAX);
+        write_i128(&mut b, i128::MIN);
+        assert_eq!(b.len(), 32);
+        assert_eq!(read_i128(&b[..16]), Some(i128::MAX));
+        assert_eq!(read_i128(&b[16..]), Some(i128::MIN));
+    }
+    #[test]
+    fn test_read() {
+        assert_eq!(read_i128(&[]), Some(0));
+        assert_eq!(read_i128(&[0xff, 0x7f]), Some(-129));
+        assert_eq!(read_i128(&[0x00, 0x80]), Some(128));
+        assert_eq!(read_i128(&[0; 17]), None);
     }
 }
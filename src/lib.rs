@@ -2,14 +2,22 @@ use pyo3::prelude::*;
 
 mod db;
 mod deserialize;
+mod gc;
 mod hex;
 mod int;
 mod mapping;
 mod nohash;
+mod qualname;
 mod serialize;
 mod token;
+mod varint;
 
 #[pymodule(name = "stash")]
 fn stash_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add(
+        "UnresolvableError",
+        m.py().get_type::<serialize::UnresolvableError>(),
+    )?;
+    m.add_class::<gc::Report>()?;
     db::populate_module(m)
 }
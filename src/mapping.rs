@@ -1,29 +1,128 @@
-use crate::hex::Hex;
+use crate::{hex::Hex, varint};
 use pyo3::{
-    exceptions::{PyException, PyKeyError, PyLookupError},
+    exceptions::{PyException, PyKeyError, PyLookupError, PyValueError},
+    prelude::*,
+    types::{PyBytes, PyIterator, PyList},
     PyErr,
 };
-use std::{fmt::Display, ops::Deref};
+use std::{
+    fmt::Display,
+    io::{Read, Result as IoResult},
+    ops::Deref,
+};
 
 pub const NBYTES: usize = 16; // 128 bit
 pub type Key = [u8; NBYTES];
 
-fn digest(b: &[u8]) -> Key {
+pub fn digest(b: &[u8]) -> Key {
     cityhash_rs::cityhash_110_128(b).to_le_bytes()
 }
 
+// The first byte of every blob, which identifies the blob format. The content of blobs that were
+// stored by earlier versions starts with a token, which are all smaller.
+const FORMAT: u8 = 0x80;
+
+// A blob consists of the format byte, the orderings of its unordered objects and the references
+// between its shared objects, each preceded by their length, followed by the serialized content.
+// Only the content is hashed, so that blobs that differ only in their orderings or references are
+// considered equal.
+pub fn new_blob(orderings: &[u8], references: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(orderings.len() + references.len() + content.len() + 5);
+    b.push(FORMAT);
+    varint::write(&mut b, orderings.len());
+    b.extend_from_slice(orderings);
+    varint::write(&mut b, references.len());
+    b.extend_from_slice(references);
+    b.extend_from_slice(content);
+    b
+}
+
+// Check the format byte of a blob.
+fn check_format(format: Option<u8>) -> IoResult<()> {
+    match format {
+        Some(FORMAT) => Ok(()),
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unsupported blob format; the blob was stored by an earlier version of stash",
+        )),
+        None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+// Split a blob into its orderings, references and content.
+pub fn split_blob(b: &[u8]) -> IoResult<(&[u8], &[u8], &[u8])> {
+    check_format(b.first().copied())?;
+    let mut b = &b[1..];
+    let mut prefix = || {
+        let n = varint::read(&mut b)?;
+        if n > b.len() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        let part;
+        (part, b) = b.split_at(n);
+        Ok(part)
+    };
+    let orderings = prefix()?;
+    let references = prefix()?;
+    Ok((orderings, references, b))
+}
+
+pub fn content_equals(mut reader: impl Read, b: &[u8]) -> IoResult<bool> {
+    let mut format = [0];
+    reader.read_exact(&mut format)?;
+    check_format(Some(format[0]))?;
+    for _ in 0..2 {
+        let n = varint::read(&mut reader)?;
+        std::io::copy(&mut (&mut reader).take(n as u64), &mut std::io::sink())?;
+    }
+    let (_, _, mut data) = split_blob(b)?;
+    let mut buf = [0; 131072]; // 128 KB; https://eklitzke.org/efficient-file-copying-on-linux
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(data.is_empty());
+        }
+        if n > data.len() || buf[..n] != data[..n] {
+            return Ok(false);
+        }
+        data = &data[n..];
+    }
+}
+
+// Extract a hash from a bytes object of the appropriate length.
+pub fn extract_key(obj: &Bound<PyAny>) -> PyResult<Key> {
+    obj.downcast_exact::<PyBytes>()?
+        .as_bytes()
+        .try_into()
+        .map_err(|_| PyValueError::new_err("invalid hash"))
+}
+
+// Create an iterator over hashes as bytes objects.
+pub fn iter_keys(py: Python<'_>, keys: Vec<Key>) -> PyResult<Bound<'_, PyIterator>> {
+    let keys = keys.iter().map(|h| PyBytes::new(py, h));
+    PyList::new(py, keys)?.try_iter()
+}
+
 pub trait Put {
     fn put(&mut self, h: Key, b: impl AsRef<[u8]>) -> MappingResult<()>;
+    fn delete(&mut self, h: Key) -> MappingResult<()>;
     // default implementation
     fn put_blob(&mut self, b: impl AsRef<[u8]>) -> MappingResult<Key> {
-        let h = digest(b.as_ref());
+        let (_, _, content) = split_blob(b.as_ref())?;
+        let h = digest(content);
         self.put(h, b).and(Ok(h))
     }
 }
 
 pub trait Get {
     fn get(&self, h: Key) -> MappingResult<impl Deref<Target = [u8]>>;
+    fn contains(&self, h: Key) -> MappingResult<bool>;
+    fn keys(&self) -> MappingResult<Vec<Key>>;
+    fn len(&self) -> MappingResult<usize>;
     // default implementation
+    fn size(&self, h: Key) -> MappingResult<usize> {
+        Ok(self.get(h)?.len())
+    }
     fn get_blob<'a>(&self, b: &'a [u8]) -> MappingResult<(impl Deref<Target = [u8]>, &'a [u8])> {
         let (left, right) = b.split_at(NBYTES);
         Ok((self.get(left.try_into().unwrap())?, right))
@@ -0,0 +1,37 @@
+use pyo3::prelude::*;
+
+// Resolve a dotted qualified name by walking its components attribute by attribute. An empty
+// qualified name resolves to the object itself.
+pub fn resolve<'py>(obj: Bound<'py, PyAny>, qualname: &str) -> PyResult<Bound<'py, PyAny>> {
+    if qualname.is_empty() {
+        return Ok(obj);
+    }
+    qualname
+        .split('.')
+        .try_fold(obj, |obj, name| obj.getattr(name))
+}
+
+// Test if a qualified name can be resolved, which is not the case for lambdas and for objects that
+// are defined inside a function.
+pub fn is_resolvable(qualname: &str) -> bool {
+    !qualname
+        .split('.')
+        .any(|name| name == "<locals>" || name == "<lambda>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolvable() {
+        assert!(is_resolvable("f"));
+        assert!(is_resolvable("Outer.Inner.f"));
+    }
+    #[test]
+    fn test_unresolvable() {
+        assert!(!is_resolvable("<lambda>"));
+        assert!(!is_resolvable("f.<locals>.g"));
+        assert!(!is_resolvable("Outer.f.<locals>.<lambda>"));
+    }
+}
@@ -1,10 +1,13 @@
 use crate::{
     int::Int,
-    mapping::{Put, NBYTES},
-    token,
+    mapping::{new_blob, Put, NBYTES},
+    qualname, token, varint,
 };
+#[cfg(not(feature = "abi3"))]
+use pyo3::{buffer::PyBuffer, types::PyMemoryView};
 use pyo3::{
-    exceptions::PyTypeError,
+    create_exception,
+    exceptions::{PyTypeError, PyValueError},
     intern,
     prelude::*,
     types::{
@@ -14,19 +17,104 @@ use pyo3::{
 };
 use std::collections::hash_map::HashMap;
 
+// The pickle protocol passed to `__reduce_ex__`. Protocol 4 is the highest protocol that does not
+// produce out-of-band buffers.
+const REDUCE_PROTOCOL: u8 = 4;
+
+// The attributes of a code object that define its behaviour, which excludes the file name and line
+// number information. Attributes that do not exist in the running Python version are skipped.
+const CODE_FIELDS: [&str; 15] = [
+    "co_argcount",
+    "co_posonlyargcount",
+    "co_kwonlyargcount",
+    "co_nlocals",
+    "co_stacksize",
+    "co_flags",
+    "co_code",
+    "co_consts",
+    "co_names",
+    "co_varnames",
+    "co_freevars",
+    "co_cellvars",
+    "co_name",
+    "co_qualname",
+    "co_exceptiontable",
+];
+
+// The standard library value types that are serialized by a dedicated token, by module and name.
+// Subclasses are not included, as they may carry additional state, and are reduced instead.
+const NATIVE_TYPES: [(&str, &str, u8); 18] = [
+    ("builtins", "complex", token::COMPLEX),
+    ("builtins", "range", token::RANGE),
+    ("builtins", "slice", token::SLICE),
+    ("decimal", "Decimal", token::DECIMAL),
+    ("fractions", "Fraction", token::FRACTION),
+    ("uuid", "UUID", token::UUID),
+    ("datetime", "date", token::DATE),
+    ("datetime", "time", token::TIME),
+    ("datetime", "datetime", token::DATETIME),
+    ("datetime", "timedelta", token::TIMEDELTA),
+    ("datetime", "timezone", token::TIMEZONE),
+    ("pathlib", "PurePosixPath", token::PATH),
+    ("pathlib", "PureWindowsPath", token::PATH),
+    ("pathlib", "PosixPath", token::PATH),
+    ("pathlib", "WindowsPath", token::PATH),
+    ("builtins", "memoryview", token::MEMORYVIEW),
+    ("array", "array", token::ARRAY),
+    ("numpy", "ndarray", token::NDARRAY),
+];
+
+create_exception!(
+    stash,
+    UnresolvableError,
+    PyTypeError,
+    "Object cannot be resolved by its qualified name."
+);
+
+// The manner in which function objects are serialized.
+#[derive(Clone, Copy)]
+pub enum FunctionMode {
+    // Serialize functions by their qualified name, and refuse functions that cannot be resolved.
+    Name,
+    // Serialize functions by their qualified name, or by their code if they cannot be resolved.
+    Code,
+    // Serialize functions by their code, including the global objects they reference.
+    Content,
+}
+
+impl FromPyObject<'_> for FunctionMode {
+    fn extract_bound(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        match obj.downcast::<PyString>()?.to_cow()?.as_ref() {
+            "name" => Ok(Self::Name),
+            "code" => Ok(Self::Code),
+            "content" => Ok(Self::Content),
+            mode => Err(PyValueError::new_err(format!(
+                "invalid function mode {:?}; expected 'name', 'code' or 'content'",
+                mode
+            ))),
+        }
+    }
+}
+
 pub fn serialize<'py, M: Put>(
     obj: &Bound<'py, PyAny>,
     db: &mut M,
+    functions: FunctionMode,
 ) -> PyResult<Bound<'py, PyBytes>> {
     let mut v: Vec<u8> = Vec::with_capacity(255);
-    let helpers = &Helpers::new(obj.py())?;
-    let keep_alive = &mut Vec::new();
-    serialize_chunk(obj, db, &mut v, helpers, keep_alive, &mut HashMap::new())?;
+    let helpers = &Helpers::new(obj.py(), functions)?;
+    let backrefs = &mut Backrefs {
+        outermost: usize::MAX,
+        ..Default::default()
+    };
+    scan(obj, helpers, backrefs)?;
+    serialize_chunk(obj, db, &mut v, helpers, backrefs, &mut HashMap::new())?;
     let hash;
     let h = if v[0] == 0 {
         &v[1..]
     } else {
-        hash = db.put_blob(&v[1..])?;
+        let references = backrefs.references();
+        hash = db.put_blob(new_blob(&backrefs.orderings, &references, &v[1..]))?;
         &hash
     };
     Ok(PyBytes::new(obj.py(), h))
@@ -37,45 +125,272 @@ struct Helpers<'py> {
     modules: HashMap<String, Bound<'py, PyAny>>,
     int: Int<'py>,
     function_type: Bound<'py, PyAny>,
+    code_type: Bound<'py, PyAny>,
+    native: HashMap<*mut pyo3::ffi::PyObject, u8>,
+    enum_type: Option<Bound<'py, PyAny>>,
+    functions: FunctionMode,
 }
 
+// The raw data and items that make up the serialization of a natively supported value.
+type Native<'py> = (Vec<u8>, Vec<Bound<'py, PyAny>>);
+
+// The serialization and orderings of a previously seen object.
+type Seen = (Box<[u8]>, Box<[u8]>);
+
 impl<'py> Helpers<'py> {
-    fn new(py: Python<'py>) -> PyResult<Self> {
+    fn new(py: Python<'py>, functions: FunctionMode) -> PyResult<Self> {
         let dispatch_table = PyModule::import(py, "copyreg")?
             .getattr("dispatch_table")?
             .downcast_exact::<PyDict>()?
             .clone();
-        let function_type = PyModule::import(py, "types")?
-            .getattr("FunctionType")?
-            .clone();
-        let modules = PyModule::import(py, "sys")?.getattr("modules")?.extract()?;
+        let types = PyModule::import(py, "types")?;
+        let function_type = types.getattr("FunctionType")?;
+        let code_type = types.getattr("CodeType")?;
+        let modules: HashMap<String, Bound<'py, PyAny>> =
+            PyModule::import(py, "sys")?.getattr("modules")?.extract()?;
         let int = Int::new(py)?;
+        // Types of modules that were never imported cannot have instances, so there is no need to
+        // import them here.
+        let mut native = HashMap::new();
+        for (module, name, token) in NATIVE_TYPES {
+            if let Some(t) = modules.get(module).and_then(|m| m.getattr(name).ok()) {
+                let _ = native.insert(t.as_ptr(), token);
+            }
+        }
+        let enum_type = modules.get("enum").map(|m| m.getattr("Enum")).transpose()?;
         Ok(Self {
             dispatch_table,
             modules,
             int,
             function_type,
+            code_type,
+            native,
+            enum_type,
+            functions,
         })
     }
     fn isfunction(&self, obj: &Bound<'py, PyAny>) -> PyResult<bool> {
         obj.is_instance(&self.function_type)
     }
+    fn iscode(&self, obj: &Bound<'py, PyAny>) -> PyResult<bool> {
+        obj.is_instance(&self.code_type)
+    }
+    // Return the token of a natively supported value, or None if the object is to be reduced.
+    // Arrays are supported only if their data type can be restored from its string form.
+    fn native_token(&self, obj: &Bound<'py, PyAny>) -> PyResult<Option<u8>> {
+        let py = obj.py();
+        if obj.is(&py.Ellipsis()) {
+            return Ok(Some(token::ELLIPSIS));
+        }
+        if let Some(token) = self.native.get(&obj.get_type().as_ptr()) {
+            if *token == token::NDARRAY {
+                let dtype = obj.getattr(intern!(py, "dtype"))?;
+                if dtype.getattr(intern!(py, "hasobject"))?.is_truthy()?
+                    || !dtype.getattr(intern!(py, "fields"))?.is_none()
+                    || !dtype.getattr(intern!(py, "subdtype"))?.is_none()
+                {
+                    return Ok(None);
+                }
+            }
+            return Ok(Some(*token));
+        }
+        if let Some(t) = &self.enum_type {
+            if obj.is_instance(t)? {
+                return Ok(Some(token::ENUM));
+            }
+        }
+        Ok(None)
+    }
+    // Reduce a natively supported value to the raw data that encodes its fields in a version
+    // independent manner, and the items to be serialized as chunks after the raw data. The
+    // contents of a buffer are not included, as they are serialized by `serialize_buffer`.
+    fn native(&self, obj: &Bound<'py, PyAny>, token: u8) -> PyResult<Native<'py>> {
+        let py = obj.py();
+        let mut data = Vec::new();
+        let mut items = Vec::new();
+        let field = |name: &str| obj.getattr(name);
+        let number = |name: &str| field(name)?.extract::<u32>();
+        match token {
+            token::COMPLEX => {
+                data.extend_from_slice(&field("real")?.extract::<f64>()?.to_le_bytes());
+                data.extend_from_slice(&field("imag")?.extract::<f64>()?.to_le_bytes());
+            }
+            token::RANGE | token::SLICE => {
+                items.extend([field("start")?, field("stop")?, field("step")?]);
+            }
+            token::DECIMAL => data.extend_from_slice(obj.str()?.to_cow()?.as_bytes()),
+            token::FRACTION => items.extend([field("numerator")?, field("denominator")?]),
+            token::UUID => {
+                data.extend_from_slice(field("bytes")?.downcast_exact::<PyBytes>()?.as_bytes())
+            }
+            token::DATE | token::DATETIME => {
+                data.extend_from_slice(&(number("year")? as u16).to_be_bytes());
+                data.push(number("month")? as u8);
+                data.push(number("day")? as u8);
+            }
+            token::TIMEDELTA => {
+                data.extend_from_slice(&field("days")?.extract::<i32>()?.to_be_bytes());
+                data.extend_from_slice(&number("seconds")?.to_be_bytes()[1..]);
+                data.extend_from_slice(&number("microseconds")?.to_be_bytes()[1..]);
+            }
+            token::TIMEZONE => {
+                items.extend(
+                    obj.call_method0(intern!(py, "__getinitargs__"))?
+                        .try_iter()?
+                        .collect::<PyResult<Vec<_>>>()?,
+                );
+            }
+            token::PATH => {
+                let name = obj.get_type().name()?;
+                let flavour = token::PATH_TYPES
+                    .iter()
+                    .position(|t| name == *t)
+                    .expect("path type is listed in PATH_TYPES");
+                data.push(flavour as u8);
+                data.extend_from_slice(obj.str()?.to_cow()?.as_bytes());
+            }
+            token::MEMORYVIEW => {
+                // Views are restored by casting a flat buffer, which supports native single
+                // character formats only.
+                let format = field("format")?;
+                let f = format.downcast_exact::<PyString>()?.to_cow()?;
+                if f.trim_start_matches('@').len() != 1 {
+                    return Err(PyTypeError::new_err(format!(
+                        "cannot dump memoryview with format {:?}",
+                        f
+                    )));
+                }
+                items.extend([format.clone(), field("shape")?, field("readonly")?]);
+            }
+            token::ARRAY => {
+                items.push(field("typecode")?);
+            }
+            token::NDARRAY => {
+                items.push(field("dtype")?.getattr(intern!(py, "str"))?);
+                items.push(field("shape")?);
+            }
+            token::ENUM => {
+                items.extend([
+                    obj.get_type().into_any(),
+                    obj.getattr(intern!(py, "_value_"))?,
+                ]);
+            }
+            _ => {}
+        }
+        // Times and datetimes share the time fields, which follow the date fields.
+        if token == token::TIME || token == token::DATETIME {
+            data.push(number("hour")? as u8);
+            data.push(number("minute")? as u8);
+            data.push(number("second")? as u8);
+            data.extend_from_slice(&number("microsecond")?.to_be_bytes()[1..]);
+            data.push(number("fold")? as u8);
+            let tzinfo = field("tzinfo")?;
+            if !tzinfo.is_none() {
+                items.push(tzinfo);
+            }
+        }
+        Ok((data, items))
+    }
+    // Test if a function object is to be serialized by its code rather than its qualified name.
+    fn by_code(&self, obj: &Bound<'py, PyAny>) -> PyResult<bool> {
+        Ok(match self.functions {
+            FunctionMode::Name => false,
+            FunctionMode::Code => {
+                let name = obj.getattr(intern!(obj.py(), "__qualname__"))?;
+                !qualname::is_resolvable(&name.downcast_exact::<PyString>()?.to_cow()?)
+            }
+            FunctionMode::Content => true,
+        })
+    }
+    // Reduce a function object to its module name, code, defaults, keyword defaults and the
+    // contents of its closure cells, followed by the dictionary of global objects that it
+    // references if functions are serialized by content.
+    fn reduce_function(&self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let py = obj.py();
+        let closure = obj.getattr(intern!(py, "__closure__"))?;
+        let contents = if closure.is_none() {
+            closure
+        } else {
+            let contents = closure
+                .try_iter()?
+                .map(|cell| cell?.getattr(intern!(py, "cell_contents")))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyTuple::new(py, contents)?.into_any()
+        };
+        let code = obj.getattr(intern!(py, "__code__"))?;
+        let mut items = vec![
+            obj.getattr(intern!(py, "__module__"))?,
+            code.clone(),
+            obj.getattr(intern!(py, "__defaults__"))?,
+            obj.getattr(intern!(py, "__kwdefaults__"))?,
+            contents,
+        ];
+        if let FunctionMode::Content = self.functions {
+            let globals = obj.getattr(intern!(py, "__globals__"))?;
+            let referenced = PyDict::new(py);
+            self.collect_globals(&code, &globals, &referenced)?;
+            items.push(referenced.into_any());
+        }
+        Ok(PyTuple::new(py, items)?.into_any())
+    }
+    // Collect the global objects that are referenced by a code object or its nested code objects.
+    // Names that are not found in `globals` refer to builtins or attributes, and are skipped.
+    fn collect_globals(
+        &self,
+        code: &Bound<'py, PyAny>,
+        globals: &Bound<'py, PyAny>,
+        referenced: &Bound<'py, PyDict>,
+    ) -> PyResult<()> {
+        let py = code.py();
+        for name in code.getattr(intern!(py, "co_names"))?.try_iter()? {
+            let name = name?;
+            if let Ok(value) = globals.get_item(&name) {
+                referenced.set_item(name, value)?;
+            }
+        }
+        for item in code.getattr(intern!(py, "co_consts"))?.try_iter()? {
+            let item = item?;
+            if self.iscode(&item)? {
+                self.collect_globals(&item, globals, referenced)?;
+            }
+        }
+        Ok(())
+    }
+    // Reduce a code object to a single item tuple containing the dictionary of its fields.
+    fn reduce_code(&self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let fields = PyDict::new(obj.py());
+        for name in CODE_FIELDS {
+            if let Ok(value) = obj.getattr(name) {
+                fields.set_item(name, value)?;
+            }
+        }
+        Ok(PyTuple::new(obj.py(), [fields])?.into_any())
+    }
     fn extend_global(
         &self,
         v: &mut Vec<u8>,
         obj: &Bound<PyAny>,
         name: &Bound<PyString>,
     ) -> PyResult<()> {
+        let name = name.to_cow()?;
+        if !qualname::is_resolvable(&name) {
+            return Err(UnresolvableError::new_err(format!(
+                "cannot dump {}: local object {} cannot be resolved by its qualified name",
+                obj, name
+            )));
+        }
         v.push(token::GLOBAL);
         if let Ok(module) = obj.getattr(intern!(obj.py(), "__module__")) {
             v.extend_from_slice(module.downcast_exact::<PyString>()?.to_cow()?.as_bytes());
         } else if let Some(module_name) = self
             .modules
             .iter()
-            .filter_map(|(module_name, module)| match module.getattr(name) {
-                Ok(found_obj) if found_obj.is(obj) => Some(module_name),
-                _ => None,
-            })
+            .filter_map(
+                |(module_name, module)| match qualname::resolve(module.clone(), &name) {
+                    Ok(found_obj) if found_obj.is(obj) => Some(module_name),
+                    _ => None,
+                },
+            )
             .next()
         {
             v.extend_from_slice(module_name.as_bytes());
@@ -83,21 +398,268 @@ impl<'py> Helpers<'py> {
             v.extend_from_slice("__main__".as_bytes())
         }
         v.extend_from_slice(":".as_bytes());
-        v.extend_from_slice(name.to_cow()?.as_bytes());
+        v.extend_from_slice(name.as_bytes());
         Ok(())
     }
-    fn get_reduce(&self, objtype: Bound<'py, PyType>) -> PyResult<Option<Bound<'py, PyAny>>> {
-        if let Some(reduce) = self.dispatch_table.get_item(&objtype)? {
-            Ok(Some(reduce))
-        } else if let Ok(reduce) = objtype.getattr(intern!(objtype.py(), "__reduce__")) {
-            Ok(Some(reduce))
+    // Reduce an object to a qualified name or a reduction tuple
+    //
+    // The reduction tuple is normalized such that the list and dictionary item iterators, if
+    // present, are collected into lists, and trailing None items are removed.
+    fn reduce(&self, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let py = obj.py();
+        let reduced = if let Some(reduce) = self.dispatch_table.get_item(obj.get_type())? {
+            reduce.call1((obj,))?
+        } else {
+            obj.call_method1(intern!(py, "__reduce_ex__"), (REDUCE_PROTOCOL,))?
+        };
+        let Ok(t) = reduced.downcast_exact::<PyTuple>() else {
+            return Ok(reduced);
+        };
+        if !(2..=6).contains(&t.len()) {
+            return Err(PyTypeError::new_err("invalid return value for reduce"));
+        }
+        let mut items: Vec<Bound<'py, PyAny>> = t.iter().collect();
+        for item in items.iter_mut().take(5).skip(3) {
+            if !item.is_none() {
+                let collected = item.try_iter()?.collect::<PyResult<Vec<_>>>()?;
+                *item = PyList::new(py, collected)?.into_any();
+            }
+        }
+        while items.len() > 2 && items.last().is_some_and(|item| item.is_none()) {
+            let _ = items.pop();
+        }
+        Ok(PyTuple::new(py, items)?.into_any())
+    }
+}
+
+// Structure to keep track of object references and orderings
+//
+// Mutable objects and reduced objects are identified by their address, such that multiple
+// references to the same object can be restored as such, and cyclic structures can be serialized
+// at all. The object graph is scanned in advance to count the number of times every object is
+// encountered, so that the objects that are shared are known upon their first serialization. The
+// reductions formed during this scan are stored for reuse, which also keeps the reduced objects
+// alive for the duration of the serialization so that their addresses cannot be reused by other
+// objects down the line.
+//
+// Neither the sharing of objects nor the order in which they are encountered contributes to the
+// hash. An object that is encountered again is serialized in full every time, and only a reference
+// to one of the objects that enclose it, which is what makes a structure cyclic, is serialized as a
+// back reference, by the number of levels between them. Which chunks represent the same shared
+// object is recorded in the references vector instead, by the position of every chunk in the
+// serialization. The orderings vector holds the permutations that undo the sorting of sets and
+// dictionaries, in the order in which the deserialization will encounter them. Both are stored
+// alongside, but separate from, the serialization, so that the original objects can be restored
+// without affecting the hash.
+#[derive(Default)]
+struct Backrefs<'py> {
+    counts: HashMap<*mut pyo3::ffi::PyObject, usize>,
+    reduced: HashMap<*mut pyo3::ffi::PyObject, Bound<'py, PyAny>>,
+    orderings: Vec<u8>,
+    // The index of the current chunk within its parent at every level, in serialization order,
+    // followed by the index of the last child of the current chunk.
+    path: Vec<usize>,
+    // The level of the enclosing objects that can be referenced by a back reference.
+    ancestors: HashMap<*mut pyo3::ffi::PyObject, usize>,
+    // The outermost level that was referenced by a back reference in the current chunk.
+    outermost: usize,
+    // The index of every shared object, and the position and index of every chunk that represents
+    // a shared object.
+    groups: HashMap<*mut pyo3::ffi::PyObject, usize>,
+    positions: Vec<(Vec<usize>, usize)>,
+    // Nonzero while serializing an object that was serialized before, whose chunks need not be
+    // recorded.
+    quiet: usize,
+}
+
+impl Backrefs<'_> {
+    // Start the next chunk of the current chunk, and return the level of the new chunk.
+    fn enter(&mut self) -> usize {
+        if let Some(last) = self.path.last_mut() {
+            *last = last.wrapping_add(1);
+        }
+        self.path.push(usize::MAX);
+        self.path.len() - 1
+    }
+    // Finish the current chunk.
+    fn leave(&mut self, obj: &Bound<PyAny>) {
+        let level = self.path.len() - 1;
+        if self.ancestors.get(&obj.as_ptr()) == Some(&level) {
+            let _ = self.ancestors.remove(&obj.as_ptr());
+        }
+        let _ = self.path.pop();
+    }
+    // Allow the object of the current chunk to be referenced by the chunks it encloses, and
+    // record its position if it is shared.
+    fn reference(&mut self, obj: &Bound<PyAny>) {
+        let level = self.path.len() - 1;
+        let _ = self.ancestors.insert(obj.as_ptr(), level);
+        if self
+            .counts
+            .get(&obj.as_ptr())
+            .is_some_and(|count| *count > 1)
+        {
+            let index = self.groups.len();
+            if let std::collections::hash_map::Entry::Vacant(e) = self.groups.entry(obj.as_ptr()) {
+                let _ = e.insert(index);
+                if self.quiet == 0 {
+                    self.positions.push((self.path[..level].to_vec(), index));
+                }
+            }
+        }
+    }
+    // Record the position of another chunk of a shared object that was serialized before, and
+    // return whether the object was serialized before.
+    fn repeat(&mut self, obj: &Bound<PyAny>) -> bool {
+        let Some(index) = self.groups.get(&obj.as_ptr()) else {
+            return false;
+        };
+        if self.quiet == 0 {
+            let level = self.path.len() - 1;
+            self.positions.push((self.path[..level].to_vec(), *index));
+        }
+        true
+    }
+    // Update the positions that were recorded since `start` within the groups of N chunks of the
+    // current chunk, after the groups were sorted.
+    fn sort_positions<const N: usize>(&mut self, start: usize, perm: &[usize]) {
+        let level = self.path.len() - 1;
+        for (path, _) in &mut self.positions[start..] {
+            let i = path[level];
+            path[level] = perm[i / N] * N + i % N;
+        }
+    }
+    fn insert_ordering(&mut self, pos: usize, perm: &[usize]) {
+        let mut b = Vec::new();
+        if perm.iter().enumerate().any(|(i, j)| i != *j) {
+            varint::write(&mut b, perm.len());
+            for j in perm {
+                varint::write(&mut b, *j);
+            }
+        } else {
+            varint::write(&mut b, 0);
+        }
+        let _ = self.orderings.splice(pos..pos, b);
+    }
+    // Append a back reference to the object the given number of levels above the current chunk.
+    fn extend_backref(&mut self, v: &mut Vec<u8>, ancestor: usize) {
+        let level = self.path.len() - 1;
+        let bytes = (level - 1 - ancestor).to_be_bytes();
+        let bytes = &bytes[bytes.iter().take_while(|b| **b == 0).count()..];
+        v.push(1 + bytes.len() as u8);
+        v.push(token::BACKREF);
+        v.extend_from_slice(bytes);
+        self.outermost = self.outermost.min(ancestor);
+    }
+    // Encode the positions of the shared objects that are represented by more than one chunk.
+    fn references(&self) -> Vec<u8> {
+        let mut counts = vec![0; self.groups.len()];
+        for (_, index) in &self.positions {
+            counts[*index] += 1;
+        }
+        let mut indices = HashMap::new();
+        let mut b = Vec::new();
+        for (path, index) in &self.positions {
+            if counts[*index] > 1 {
+                let n = indices.len();
+                varint::write(&mut b, *indices.entry(*index).or_insert(n));
+                varint::write(&mut b, path.len());
+                for i in path {
+                    varint::write(&mut b, *i);
+                }
+            }
+        }
+        b
+    }
+}
+
+// Count object references in advance of serialization
+//
+// This routine traverses the object graph in the same order as serialize_chunk and counts the
+// number of times every container or reduced object is encountered. Mutable objects are traversed
+// only once; immutable tuples and frozensets are traversed a second time, so that mutable objects
+// contained in a shared tuple are counted as shared, too.
+//
+// * `obj` - Python object to be scanned.
+// * `helpers` - Helper object containing a `dispatch_table`, `modules` and `int` member.
+// * `backrefs` - Structure to keep track of object references.
+fn scan<'py>(
+    obj: &Bound<'py, PyAny>,
+    helpers: &Helpers<'py>,
+    backrefs: &mut Backrefs<'py>,
+) -> PyResult<()> {
+    if obj.downcast_exact::<PyString>().is_ok()
+        || obj.downcast_exact::<PyBytes>().is_ok()
+        || obj.downcast_exact::<PyInt>().is_ok()
+        || obj.downcast_exact::<PyFloat>().is_ok()
+        || obj.is_none()
+        || obj.downcast_exact::<PyBool>().is_ok()
+        || helpers.isfunction(obj)? && !helpers.by_code(obj)?
+        || obj.downcast::<PyType>().is_ok()
+        || obj.downcast_exact::<PyModule>().is_ok()
+    {
+        return Ok(());
+    }
+    let count = backrefs.counts.entry(obj.as_ptr()).or_insert(0);
+    *count += 1;
+    let count = *count;
+    if let Ok(t) = obj.downcast_exact::<PyTuple>() {
+        if count <= 2 {
+            for item in t {
+                scan(&item, helpers, backrefs)?;
+            }
+        }
+    } else if let Ok(s) = obj.downcast_exact::<PyFrozenSet>() {
+        if count <= 2 {
+            for item in s.iter() {
+                scan(&item, helpers, backrefs)?;
+            }
+        }
+    } else if let Some(token) = helpers.native_token(obj)? {
+        // Standard library values are immutable, and are traversed like tuples. Buffers consist
+        // of strings, integers and bytes only, and need not be traversed at all.
+        if count <= 2 && !matches!(token, token::MEMORYVIEW | token::ARRAY | token::NDARRAY) {
+            for item in helpers.native(obj, token)?.1 {
+                scan(&item, helpers, backrefs)?;
+            }
+        }
+    } else if count > 1 || obj.downcast_exact::<PyByteArray>().is_ok() {
+        // A mutable object that was encountered before need not be traversed again.
+    } else if let Ok(l) = obj.downcast_exact::<PyList>() {
+        for item in l {
+            scan(&item, helpers, backrefs)?;
+        }
+    } else if let Ok(s) = obj.downcast_exact::<PySet>() {
+        for item in s.iter() {
+            scan(&item, helpers, backrefs)?;
+        }
+    } else if let Ok(s) = obj.downcast_exact::<PyDict>() {
+        for (key, value) in s.iter() {
+            scan(&key, helpers, backrefs)?;
+            scan(&value, helpers, backrefs)?;
+        }
+    } else {
+        let reduced = if helpers.isfunction(obj)? {
+            helpers.reduce_function(obj)?
+        } else if helpers.iscode(obj)? {
+            helpers.reduce_code(obj)?
         } else {
-            Ok(None)
+            helpers.reduce(obj)?
+        };
+        if let Ok(t) = reduced.downcast_exact::<PyTuple>() {
+            for item in t {
+                scan(&item, helpers, backrefs)?;
+            }
         }
+        let _ = backrefs.reduced.insert(obj.as_ptr(), reduced);
     }
+    Ok(())
 }
 
-fn sort_chunks<const N: usize>(v: &mut [u8]) {
+// Sort groups of N chunks in ascending order
+//
+// Returns the permutation that maps the original position of every group to its sorted position.
+fn sort_chunks<const N: usize>(v: &mut [u8]) -> Vec<usize> {
     let copy: Box<[u8]> = v.into();
     let mut chunks = Vec::<&[u8]>::new();
     let mut left;
@@ -111,13 +673,62 @@ fn sort_chunks<const N: usize>(v: &mut [u8]) {
         (left, right) = right.split_at(i);
         chunks.push(left);
     }
-    chunks.sort();
+    let mut argsort: Vec<usize> = (0..chunks.len()).collect();
+    argsort.sort_by_key(|i| chunks[*i]);
+    let mut perm = vec![0; chunks.len()];
     let mut left;
     let mut right = v;
-    for chunk in chunks {
-        (left, right) = right.split_at_mut(chunk.len());
-        left.clone_from_slice(chunk);
+    for (j, i) in argsort.into_iter().enumerate() {
+        (left, right) = right.split_at_mut(chunks[i].len());
+        left.clone_from_slice(chunks[i]);
+        perm[i] = j;
+    }
+    perm
+}
+
+// Append the contents of a buffer in C order to a byte vector
+//
+// A C-contiguous buffer with a native format is copied directly, without forming an intermediate
+// bytes object. Other buffers, and all buffers under the limited API, which offers no access to
+// buffers before Python 3.11, are copied via their `tobytes` method.
+fn extend_buffer(obj: &Bound<'_, PyAny>, v: &mut Vec<u8>) -> PyResult<()> {
+    let py = obj.py();
+    #[cfg(not(feature = "abi3"))]
+    if let Ok(view) =
+        PyMemoryView::from(obj).and_then(|m| m.call_method1(intern!(py, "cast"), ("B",)))
+    {
+        let buffer = PyBuffer::<u8>::get(&view)?;
+        let n = v.len();
+        v.resize(n + buffer.item_count(), 0);
+        return buffer.copy_to_slice(py, &mut v[n..]);
+    }
+    let b = obj.call_method0(intern!(py, "tobytes"))?;
+    v.extend_from_slice(b.downcast_exact::<PyBytes>()?.as_bytes());
+    Ok(())
+}
+
+// Append the contents of a buffer as a bytes chunk, which is identical to the serialization of
+// the bytes object returned by its `tobytes` method.
+fn serialize_buffer<M: Put>(
+    obj: &Bound<'_, PyAny>,
+    db: &mut M,
+    v: &mut Vec<u8>,
+    backrefs: &mut Backrefs<'_>,
+) -> PyResult<()> {
+    let _ = backrefs.enter();
+    let _ = backrefs.path.pop();
+    v.push(0);
+    let n = v.len();
+    v.push(token::BYTES);
+    extend_buffer(obj, v)?;
+    if let Ok(l) = (v.len() - n).try_into() {
+        v[n - 1] = l;
+    } else {
+        let hash = db.put_blob(new_blob(&[], &[], &v[n..]))?;
+        v.truncate(n);
+        v.extend_from_slice(&hash);
     }
+    Ok(())
 }
 
 // Serialize a Python object to a byte vector
@@ -131,29 +742,48 @@ fn sort_chunks<const N: usize>(v: &mut [u8]) {
 // * `obj` - Python object to be serialized.
 // * `db` - Database to store hashed blobs.
 // * `v` - Byte vector that the serialization is appended to.
-// * `backrefs` - Structure to keep track of object references and dictionary orderings.
 // * `helpers` - Helper object containing a `dispatch_table`, `modules` and `int` member.
-// * `keep_alive` - Python object vector to prevent garbage collection.
+// * `backrefs` - Structure to keep track of object references.
 // * `seen` - Hashmap with previously seen objects.
 fn serialize_chunk<'py, M: Put>(
     obj: &Bound<'py, PyAny>,
     db: &mut M,
     v: &mut Vec<u8>,
     helpers: &Helpers<'py>,
-    keep_alive: &mut Vec<Bound<'py, PyAny>>,
-    seen: &mut HashMap<*mut pyo3::ffi::PyObject, Box<[u8]>>,
+    backrefs: &mut Backrefs<'py>,
+    seen: &mut HashMap<*mut pyo3::ffi::PyObject, Seen>,
 ) -> PyResult<()> {
+    let level = backrefs.enter();
+
+    // An object that encloses this one is serialized as a back reference to it.
+    if let Some(ancestor) = backrefs.ancestors.get(&obj.as_ptr()).copied() {
+        backrefs.extend_backref(v, ancestor);
+        backrefs.leave(obj);
+        return Ok(());
+    }
+
+    // A shared object that was serialized before is serialized again, but only its own position
+    // is recorded, as the deserialization will not look inside.
+    let repeated = backrefs.repeat(obj);
+
     // The `seen` hashmap serves to speed up hashing by recognizing that an object was serialized
-    // before. It overlaps with the backrefs hashmap in that it tracks previously seen objects, but
-    // is limited to objects that resulted in long enough byte sequences to be hashed, and stores
-    // this hash. This allows the result to be kept in memory as a database reference, rather than
-    // the full serialization that would amount to duplicating the entire object in memory. We also
-    // reduce potentially expensive database operations by not writing the same entry twice.
+    // before, and stores its serialization along with its orderings. It is limited to shared
+    // objects, and to objects that contain no shared objects, such as strings and tuples of
+    // immutable objects, whose serialization does not refer to any enclosing objects. For objects
+    // that resulted in long enough byte sequences to be hashed, this allows the result to be kept
+    // in memory as a database reference, rather than the full serialization that would amount to
+    // duplicating the entire object in memory. We also reduce potentially expensive database
+    // operations by not writing the same entry twice.
 
-    if let Some(b) = seen.get(&obj.as_ptr()) {
+    if let Some((b, orderings)) = seen.get(&obj.as_ptr()) {
         v.extend_from_slice(b);
+        backrefs.orderings.extend_from_slice(orderings);
+        backrefs.leave(obj);
         return Ok(());
     }
+    if repeated {
+        backrefs.quiet += 1;
+    }
 
     // The first byte is the length of the chunk. We write a zero now and go back to replace if
     // with the actual length when we're done serializing, or leave it at zero in case the length
@@ -164,12 +794,20 @@ fn serialize_chunk<'py, M: Put>(
     // length afterward.
     let n = v.len();
 
+    // Store the current number of recorded positions and orderings, and the outermost level that
+    // was referenced so far, so that we can tell afterward if any were added while serializing
+    // this object.
+    let npositions = backrefs.positions.len();
+    let norderings = backrefs.orderings.len();
+    let outermost = std::mem::replace(&mut backrefs.outermost, usize::MAX);
+
     // We now differentiate between different Python object types by trying to downcast `obj` into
     // them one by one, or reducing it to a new form otherwise.
     if let Ok(s) = obj.downcast_exact::<PyString>() {
         v.push(token::STRING);
         v.extend_from_slice(s.to_cow()?.as_bytes());
     } else if let Ok(b) = obj.downcast_exact::<PyByteArray>() {
+        backrefs.reference(obj);
         v.push(token::BYTEARRAY);
         // SAFETY: We promise to not let the interpreter regain control or invoke any PyO3 APIs
         // while using the slice.
@@ -184,48 +822,60 @@ fn serialize_chunk<'py, M: Put>(
         v.push(token::FLOAT);
         v.extend_from_slice(&f.value().to_le_bytes());
     } else if let Ok(l) = obj.downcast_exact::<PyList>() {
+        backrefs.reference(obj);
         v.push(token::LIST);
         for item in l {
-            serialize_chunk(&item, db, v, helpers, keep_alive, seen)?;
+            serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
         }
     } else if let Ok(t) = obj.downcast_exact::<PyTuple>() {
         v.push(token::TUPLE);
         for item in t {
-            serialize_chunk(&item, db, v, helpers, keep_alive, seen)?;
+            serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
         }
     } else if let Ok(s) = obj.downcast_exact::<PySet>() {
+        backrefs.reference(obj);
+        let start = backrefs.positions.len();
+        let pos = backrefs.orderings.len();
         v.push(token::SET);
         // Since a set is an unordered object, its serialization (and hash) cannot be formed like
         // that of a list or tuple by simply iterating over its items. Instead we serialize all
-        // items separately and then add the chunks in ascending order. This, however, presents a
-        // problem for the back references, as the deserialization will need to undo this sorting
-        // to maintain coherence of the object indices. To this end we perform an argsort in case
-        // back references are present, and add it to the index vector in inverse order.
+        // items separately and then add the chunks in ascending order. To restore the original
+        // iteration order, the permutation that undoes the sorting is added to the orderings
+        // vector, which does not contribute to the hash. The positions of the shared objects
+        // within the items are updated to the sorted order.
         for item in s.iter() {
-            serialize_chunk(&item, db, v, helpers, keep_alive, seen)?;
+            serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
         }
-        sort_chunks::<1>(&mut v[n + 1..]);
+        let perm = sort_chunks::<1>(&mut v[n + 1..]);
+        backrefs.sort_positions::<1>(start, &perm);
+        backrefs.insert_ordering(pos, &perm);
     } else if let Ok(s) = obj.downcast_exact::<PyFrozenSet>() {
+        let start = backrefs.positions.len();
+        let pos = backrefs.orderings.len();
         v.push(token::FROZENSET);
         for item in s.iter() {
-            serialize_chunk(&item, db, v, helpers, keep_alive, seen)?;
+            serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
         }
-        sort_chunks::<1>(&mut v[n + 1..]);
+        let perm = sort_chunks::<1>(&mut v[n + 1..]);
+        backrefs.sort_positions::<1>(start, &perm);
+        backrefs.insert_ordering(pos, &perm);
     } else if let Ok(s) = obj.downcast_exact::<PyDict>() {
+        backrefs.reference(obj);
+        let start = backrefs.positions.len();
+        let pos = backrefs.orderings.len();
         v.push(token::DICT);
         // Since a dictionary is an unordered object as far as the equality test is concerned, its
         // serialization (and hash) cannot be formed like that of a list or tuple by simply
         // iterating over its items. Instead we serialize all items separately and then add the
-        // chunks in ascending order. This, however, presents a problem for the back references, as
-        // the deserialization will need to undo this sorting to maintain coherence of the object
-        // indices. To this end we perform an argsort in case back references are present, and add
-        // it to the index vector in inverse order. This also restores the original dictionary's
-        // insertion order.
+        // chunks in ascending order, as for sets. The permutation in the orderings vector
+        // restores the original dictionary's insertion order.
         for (key, value) in s.iter() {
-            serialize_chunk(&key, db, v, helpers, keep_alive, seen)?;
-            serialize_chunk(&value, db, v, helpers, keep_alive, seen)?;
+            serialize_chunk(&key, db, v, helpers, backrefs, seen)?;
+            serialize_chunk(&value, db, v, helpers, backrefs, seen)?;
         }
-        sort_chunks::<2>(&mut v[n + 1..]);
+        let perm = sort_chunks::<2>(&mut v[n + 1..]);
+        backrefs.sort_positions::<2>(start, &perm);
+        backrefs.insert_ordering(pos, &perm);
     } else if obj.is_none() {
         v.push(token::NONE);
     } else if let Ok(b) = obj.downcast_exact::<PyBool>() {
@@ -234,29 +884,62 @@ fn serialize_chunk<'py, M: Put>(
         } else {
             token::FALSE
         });
-    } else if helpers.isfunction(obj)? {
-        // A function object is stored by its qualified name.
-        helpers.extend_global(
-            v,
-            obj,
-            obj.getattr(intern!(obj.py(), "__name__"))?
-                .downcast_exact()?,
-        )?;
-    } else if let Ok(t) = obj.downcast_exact::<PyType>() {
+    } else if helpers.isfunction(obj)? || helpers.iscode(obj)? {
+        // A code object is stored by the dictionary of its fields, and a function object by its
+        // qualified name or, if applicable, by the items of its reduction which include its code.
+        if let Some(reduced) = backrefs.reduced.get(&obj.as_ptr()).cloned() {
+            if helpers.iscode(obj)? {
+                v.push(token::CODE);
+            } else {
+                backrefs.reference(obj);
+                v.push(token::FUNCTION);
+            }
+            for item in reduced.downcast_exact::<PyTuple>()? {
+                serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
+            }
+        } else {
+            helpers.extend_global(
+                v,
+                obj,
+                obj.getattr(intern!(obj.py(), "__qualname__"))?
+                    .downcast_exact()?,
+            )?;
+        }
+    } else if let Ok(t) = obj.downcast::<PyType>() {
         // A type object is stored by its qualified name.
         helpers.extend_global(v, obj, &t.qualname()?)?;
-    } else if let Some(reduce) = helpers.get_reduce(obj.get_type())? {
-        let reduced = reduce.call1((obj,))?;
-        // The reduce operation can either return a qualified name, or a tuple with a reduced form.
+    } else if let Ok(m) = obj.downcast_exact::<PyModule>() {
+        // A module is stored by its name, followed by an empty qualified name.
+        v.push(token::GLOBAL);
+        v.extend_from_slice(m.name()?.to_cow()?.as_bytes());
+        v.extend_from_slice(":".as_bytes());
+    } else if let Some(token) = helpers.native_token(obj)? {
+        // A standard library value is stored by its fields rather than its reduction, which is
+        // both faster and independent of the Python version. Buffers are mutable, and can
+        // therefore be shared.
+        let (data, items) = helpers.native(obj, token)?;
+        if matches!(token, token::MEMORYVIEW | token::ARRAY | token::NDARRAY) {
+            backrefs.reference(obj);
+        }
+        v.push(token);
+        v.extend_from_slice(&data);
+        for item in items {
+            serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
+        }
+        if matches!(token, token::MEMORYVIEW | token::ARRAY | token::NDARRAY) {
+            serialize_buffer(obj, db, v, backrefs)?;
+        }
+    } else if let Some(reduced) = backrefs.reduced.get(&obj.as_ptr()).cloned() {
+        // The reduce operation, which was performed in advance by `scan`, can either return a
+        // qualified name, or a tuple with a reduced form. Since the items in `reduced` are
+        // potentially newly formed, they are kept alive by `backrefs` so we can safely use their
+        // IDs in the `backrefs` and `seen` hashmaps.
         if let Ok(t) = reduced.downcast_exact::<PyTuple>() {
+            backrefs.reference(obj);
             v.push(token::REDUCE);
             for item in t {
-                serialize_chunk(&item, db, v, helpers, keep_alive, seen)?;
+                serialize_chunk(&item, db, v, helpers, backrefs, seen)?;
             }
-            // Since the items in `reduced` are potentially newly formed, we bump its reference
-            // count so we can safely use their IDs in the `backrefs` and `seen` hashmaps without
-            // risking them being reused by other objects down the line.
-            keep_alive.push(reduced);
         } else if let Ok(s) = reduced.downcast_exact::<PyString>() {
             helpers.extend_global(v, obj, s)?;
         } else {
@@ -267,19 +950,39 @@ fn serialize_chunk<'py, M: Put>(
     };
 
     // Finally, the length byte is updated to the length of the chunk. If the length exceeds 255
-    // then the chunk is added to the database and its hash written to the vector instead.
+    // then the chunk is added to the database along with its orderings, and its hash written to
+    // the vector instead. The outermost chunk also brings the references.
     if let Ok(l) = (v.len() - n).try_into() {
         v[n - 1] = l;
     } else {
-        let hash = db.put_blob(&v[n..])?;
+        let orderings = backrefs.orderings.split_off(norderings);
+        let references = if level == 0 {
+            backrefs.references()
+        } else {
+            Vec::new()
+        };
+        let hash = db.put_blob(new_blob(&orderings, &references, &v[n..]))?;
         v.truncate(n);
         v.extend_from_slice(&hash);
     }
 
-    // If there is any chance of seeing this object again, add its serialization to the seen map.
-    if obj.get_refcnt() > 2 {
-        let _ = seen.insert(obj.as_ptr(), v[n - 1..].into());
+    // If there is any chance of seeing this object again, add its serialization to the seen map,
+    // provided that it does not refer to any enclosing objects, as the back references would be
+    // different the next time, and that it is shared or contains no shared objects, as these
+    // should be recorded the next time.
+    let encloses = backrefs.outermost >= level;
+    backrefs.outermost = backrefs.outermost.min(outermost);
+    if repeated {
+        backrefs.quiet -= 1;
+    }
+    if obj.get_refcnt() > 2
+        && encloses
+        && (backrefs.groups.contains_key(&obj.as_ptr()) || backrefs.positions.len() == npositions)
+    {
+        let orderings = backrefs.orderings[norderings..].into();
+        let _ = seen.insert(obj.as_ptr(), (v[n - 1..].into(), orderings));
     }
+    backrefs.leave(obj);
 
     Ok(())
 }
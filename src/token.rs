@@ -13,3 +13,31 @@ pub const FALSE: u8 = 12;
 pub const BYTEARRAY: u8 = 13;
 pub const REDUCE: u8 = 14;
 pub const GLOBAL: u8 = 15;
+pub const BACKREF: u8 = 17;
+pub const FUNCTION: u8 = 18;
+pub const CODE: u8 = 19;
+pub const COMPLEX: u8 = 20;
+pub const RANGE: u8 = 21;
+pub const SLICE: u8 = 22;
+pub const ELLIPSIS: u8 = 23;
+pub const DECIMAL: u8 = 24;
+pub const FRACTION: u8 = 25;
+pub const UUID: u8 = 26;
+pub const DATE: u8 = 27;
+pub const TIME: u8 = 28;
+pub const DATETIME: u8 = 29;
+pub const TIMEDELTA: u8 = 30;
+pub const TIMEZONE: u8 = 31;
+pub const PATH: u8 = 32;
+pub const ENUM: u8 = 33;
+pub const MEMORYVIEW: u8 = 34;
+pub const ARRAY: u8 = 35;
+pub const NDARRAY: u8 = 36;
+
+// The pathlib types that are serialized by the path token, indexed by the flavour byte.
+pub const PATH_TYPES: [&str; 4] = [
+    "PurePosixPath",
+    "PureWindowsPath",
+    "PosixPath",
+    "WindowsPath",
+];
@@ -0,0 +1,56 @@
+use std::io::{Error, ErrorKind, Read, Result};
+
+pub fn write(v: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        v.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    v.push(n as u8);
+}
+
+pub fn read(mut reader: impl Read) -> Result<usize> {
+    let mut n = 0;
+    let mut shift = 0;
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if shift >= usize::BITS {
+            return Err(Error::new(ErrorKind::InvalidData, "varint too long"));
+        }
+        n |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] < 0x80 {
+            return Ok(n);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut v = Vec::new();
+        write(&mut v, 1);
+        write(&mut v, 300);
+        assert_eq!(v, [0x01, 0xac, 0x02])
+    }
+    #[test]
+    fn test_read() {
+        let mut b: &[u8] = &[0x01, 0xac, 0x02];
+        assert_eq!(read(&mut b).unwrap(), 1);
+        assert_eq!(read(&mut b).unwrap(), 300);
+        assert!(b.is_empty())
+    }
+    #[test]
+    fn test_read_truncated() {
+        let b: &[u8] = &[0xac];
+        assert!(read(b).is_err())
+    }
+    #[test]
+    fn test_read_too_long() {
+        let b: &[u8] = &[0xff; 16];
+        assert_eq!(read(b).unwrap_err().kind(), ErrorKind::InvalidData)
+    }
+}
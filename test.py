@@ -1,10 +1,15 @@
-import stash, math, unittest, tempfile
+import stash, math, unittest, unittest.mock, types, tempfile, os, collections, decimal, fractions, uuid, datetime, pathlib, enum, array, ctypes, multiprocessing, sqlite3
 
 try:
     import numpy
 except ImportError:
     numpy = None
 
+try:
+    import fcntl
+except ImportError:
+    fcntl = None
+
 
 class MyClass:
     def __init__(self, x):
@@ -18,10 +23,86 @@ class MyReduceableClass(MyClass):
         return MyReduceableClass, (self.x,)
 
 
+class Outer:
+    class Inner(MyClass):
+        @staticmethod
+        def method():
+            pass
+
+
+class MyList(list):
+    pass
+
+
+class MyNewArgsClass:
+    def __new__(cls, x, *, y):
+        self = super().__new__(cls)
+        self.x = x
+        self.y = y
+        return self
+    def __getnewargs_ex__(self):
+        return (self.x,), {'y': self.y}
+
+
+def set_x(obj, state):
+    obj.x = state
+
+
+class MyStateSetterClass(MyClass):
+    def __reduce__(self):
+        return MyStateSetterClass, (None,), self.x, None, None, set_x
+
+
+class MySlotsClass:
+    __slots__ = 'x',
+    def __init__(self, x):
+        self.x = x
+    def __eq__(self, other):
+        return isinstance(other, MySlotsClass) and self.x == other.x
+
+
+class MySlotsDictClass(MyClass):
+    __slots__ = 'y',
+    def __init__(self, x, y):
+        self.x = x
+        self.y = y
+    def __eq__(self, other):
+        return super().__eq__(other) and self.y == other.y
+
+
+class MyInvalidStateClass:
+    def __reduce__(self):
+        return MyInvalidStateClass, (), 5
+
+
+class MyEnum(enum.Enum):
+    A = 1
+    B = 'b', 2
+
+
+class MyFlag(enum.Flag):
+    X = 1
+    Y = 2
+
+
+OFFSET = 10
+
+def shifted(x):
+    return MyClass(x + OFFSET)
+
+def fib(n):
+    return fib(n-1) + fib(n-2) if n > 1 else n
+
+
+def fill_filedb(path, worker):
+    db = stash.FileDB(path)
+    return [db.hash([worker, i, 'x' * i]) for i in range(50)]
+
+
 class Base(unittest.TestCase):
 
-    def check(self, obj, eq=lambda x: x):
-        h = self.db.hash(obj)
+    def check(self, obj, eq=lambda x: x, **kwargs):
+        h = self.db.hash(obj, **kwargs)
         obj_ = self.db.unhash(h)
         self.assertIs(type(obj), type(obj_))
         self.assertEqual(eq(obj_), eq(obj))
@@ -36,6 +117,11 @@ class Base(unittest.TestCase):
         self.check(-128)
         self.check(-129)
 
+    def test_large_int(self):
+        for e in 63, 64, 127, 128, 1000:
+            for n in 2**e - 1, 2**e, -2**e, -2**e - 1:
+                self.check(n)
+
     def test_float(self):
         self.check(0.)
         self.check(1e15)
@@ -75,6 +161,113 @@ class Base(unittest.TestCase):
         d2 = {'b': 2, 'c': 3, 'a': 1}
         h2 = self.check(d2)
         self.assertEqual(h1, h2)
+        h3 = self.check({'a': [1], 'b': [2]})
+        h4 = self.check({'b': [2], 'a': [1]})
+        self.assertEqual(h3, h4)
+
+    def test_dict_order(self):
+        self.check({'b': 1, 'c': 2, 'a': 3}, eq=list)
+        self.check({str(i): i for i in range(100, 0, -1)}, eq=list)
+        self.check([{'b': 1, 'a': 2}, {'d': {'f': 3, 'e': 4}, 'c': 5}], eq=repr)
+
+    def test_complex(self):
+        self.check(1+2j)
+        self.check(complex(-0., math.inf))
+
+    def test_range(self):
+        self.check(range(10))
+        self.check(range(-5, 2**70, 3))
+
+    def test_slice(self):
+        self.check(slice(3))
+        self.check(slice(None, 'a', [1, 2]))
+
+    def test_ellipsis(self):
+        self.check(...)
+
+    def test_decimal(self):
+        for s in '1.10', '-0', '1E+3', 'NaN', 'sNaN12', '-Infinity':
+            self.check(decimal.Decimal(s), eq=str)
+
+    def test_fraction(self):
+        self.check(fractions.Fraction(-3, 4))
+
+    def test_uuid(self):
+        self.check(uuid.UUID('12345678-1234-5678-1234-567812345678'))
+
+    def test_datetime(self):
+        tz = datetime.timezone(datetime.timedelta(hours=-3, minutes=-30), 'NST')
+        self.check(datetime.date(2024, 2, 29))
+        self.check(datetime.time(23, 59, 58, 999999, fold=1), eq=lambda t: (t, t.fold))
+        self.check(datetime.time(12, tzinfo=tz), eq=lambda t: (t, t.tzname()))
+        self.check(datetime.datetime(1, 1, 1))
+        self.check(datetime.datetime(9999, 12, 31, 1, 2, 3, 4, tz), eq=lambda d: (d, d.tzname()))
+        self.check(datetime.datetime(2024, 1, 1, tzinfo=datetime.timezone.utc), eq=lambda d: d.tzinfo)
+        self.check(datetime.timedelta(days=-999999999, seconds=86399, microseconds=999999))
+
+    def test_path(self):
+        self.check(pathlib.PurePosixPath('/a/b'))
+        self.check(pathlib.PureWindowsPath('C:/a/b'))
+        self.check(pathlib.Path('a', 'b'))
+
+    def test_enum(self):
+        self.check(MyEnum.A)
+        self.check(MyEnum.B)
+        self.check(MyFlag.X | MyFlag.Y)
+        self.check(MyFlag(0))
+
+    def test_memoryview(self):
+        eq = lambda m: (m.format, m.shape, m.readonly, m.tolist())
+        self.check(memoryview(b'abc'), eq=eq)
+        self.check(memoryview(bytearray(range(12))).cast('i', [3]), eq=eq)
+        self.check(memoryview(bytes(range(12))).cast('B', [3, 4])[::2], eq=eq)
+        self.check(memoryview(bytes(8)).cast('@d', []), eq=eq)
+        with self.assertRaisesRegex(TypeError, 'format'):
+            self.check(memoryview((ctypes.c_int * 2)()))
+
+    def test_array(self):
+        self.check(array.array('d', [1.5, -2]), eq=lambda a: (a.typecode, a))
+        self.check(array.array('u', 'abc'), eq=lambda a: (a.typecode, a))
+        self.check(array.array('d', range(100)), eq=lambda a: (a.typecode, a))
+        a = array.array('b')
+        self.check([a, a], eq=lambda obj: obj[0] is obj[1])
+
+    def test_shared_reference(self):
+        x = [1]
+        h = self.check([x, x], eq=lambda obj: obj[0] is obj[1])
+        self.assertEqual(stash.hash([x, [1]]), h)
+        h = self.check({'a': x, 'b': x}, eq=lambda obj: obj['a'] is obj['b'])
+        self.assertEqual(stash.hash({'b': x, 'a': x}), h)
+        self.assertEqual(stash.hash({'a': x, 'b': [1]}), h)
+        y = MyClass(x)
+        h = self.check({'b': y, 'a': [y]}, eq=lambda obj: obj['b'] is obj['a'][0])
+        self.assertEqual(stash.hash({'a': [y], 'b': y}), h)
+        self.assertEqual(stash.hash({'a': [y], 'b': MyClass(x)}), h)
+
+    def test_shared_tuple(self):
+        x = [1],
+        self.check([x, x], eq=lambda obj: obj[0][0] is obj[1][0])
+
+    def test_cyclic_list(self):
+        x = [1]
+        x.append(x)
+        self.check(x, eq=lambda obj: obj[1] is obj)
+
+    def test_cyclic_dict(self):
+        d = {'a': 1}
+        d['b'] = d
+        self.check(d, eq=lambda obj: obj['b'] is obj)
+
+    def test_cyclic_tuple(self):
+        x = []
+        t = x,
+        x.append(t)
+        self.check(t, eq=lambda obj: obj[0][0][0] is obj[0])
+
+    def test_cyclic_reduce(self):
+        obj = MyClass(None)
+        obj.x = [obj]
+        self.check(obj, eq=lambda obj: obj.x[0] is obj)
 
     def test_none(self):
         self.check(None)
@@ -90,14 +283,134 @@ class Base(unittest.TestCase):
         self.check(MyClass(10))
         self.check(MyReduceableClass(10))
 
+    def test_reduce_ex(self):
+        self.check(MyNewArgsClass(10, y=20), eq=vars)
+        self.check(MyStateSetterClass(10))
+
+    def test_slots(self):
+        self.check(MySlotsClass(10))
+        self.check(MySlotsDictClass(10, 20))
+
+    def test_listitems(self):
+        obj = MyList([1, 2, 3])
+        obj.attr = 4
+        self.check(obj, eq=lambda obj: (list(obj), vars(obj)))
+
+    def test_dictitems(self):
+        self.check(collections.OrderedDict([('b', 1), ('a', 2)]), eq=list)
+        self.check(collections.defaultdict(list, a=[1]), eq=lambda obj: (obj.default_factory, obj))
+
+    def test_cyclic_dictitems(self):
+        obj = collections.OrderedDict()
+        obj['self'] = obj
+        self.check(obj, eq=lambda obj: obj['self'] is obj)
+
     def test_global(self):
         self.check(MyClass)
         self.check(MyReduceableClass)
 
+    def test_nested_global(self):
+        self.check(Outer.Inner)
+        self.check(Outer.Inner.method)
+        self.check(Outer.Inner(10))
+
+    def test_local_global(self):
+        class Local:
+            pass
+        def local():
+            pass
+        for obj in Local, local, lambda: None:
+            with self.assertRaisesRegex(stash.UnresolvableError, 'local object'):
+                self.check(obj)
+
+    def test_local_function(self):
+        n = 2
+        def local(x, y=3, *, z=4):
+            return x * n + y + z
+        self.check(local, eq=lambda f: f(1), functions='code')
+        self.check(lambda x: x + n, eq=lambda f: f(1), functions='code')
+        self.check(MyClass, functions='code')
+
+    def test_lambda_hash(self):
+        h1 = self.check(lambda x: x + 1, eq=lambda f: f(1), functions='code')
+        h2 = self.check(lambda x: x + 2, eq=lambda f: f(1), functions='code')
+        h3 = self.check(lambda x: x + 1, eq=lambda f: f(1), functions='code')
+        self.assertNotEqual(h1, h2)
+        self.assertEqual(h1, h3)
+
+    def test_closure_hash(self):
+        def make(n):
+            return lambda x: x + n
+        h1 = self.check(make(1), eq=lambda f: f(1), functions='code')
+        h2 = self.check(make(2), eq=lambda f: f(1), functions='code')
+        self.assertNotEqual(h1, h2)
+
+    def test_recursive_local_function(self):
+        def fac(n):
+            return n * fac(n-1) if n > 1 else 1
+        self.check(fac, eq=lambda f: f(5), functions='code')
+
+    def test_content_function(self):
+        self.check(shifted, eq=lambda f: f(1), functions='content')
+        self.check(fib, eq=lambda f: f(10), functions='content')
+        self.check(math.floor, functions='content')
+
+    def test_content_hash(self):
+        global OFFSET
+        h1 = self.check(shifted, eq=lambda f: f(1), functions='content')
+        OFFSET = 20
+        try:
+            h2 = self.check(shifted, eq=lambda f: f(1), functions='content')
+        finally:
+            OFFSET = 10
+        h3 = self.check(shifted, eq=lambda f: f(1), functions='content')
+        self.assertNotEqual(h1, h2)
+        self.assertEqual(h1, h3)
+        self.assertEqual(self.check(shifted), self.check(shifted, functions='name'))
+
+    def test_invalid_function_mode(self):
+        with self.assertRaises(ValueError):
+            self.check(None, functions='invalid')
+
     @unittest.skipIf(numpy is None, "numpy is not installed")
     def test_numpy_array(self):
         self.check(numpy.arange(.5, 12).reshape(3, 4), eq=numpy.ndarray.tolist)
 
+    @unittest.skipIf(numpy is None, "numpy is not installed")
+    def test_numpy_layout(self):
+        a = numpy.arange(12, dtype='>i2').reshape(3, 4)
+        h = self.check(a.T, eq=lambda a: (a.dtype, a.shape, a.flags.writeable, a.tolist()))
+        self.assertEqual(h, self.check(numpy.ascontiguousarray(a.T)))
+        self.check(numpy.array(['a', 'bc']), eq=numpy.ndarray.tolist)
+        self.check(numpy.array(1.5), eq=numpy.ndarray.tolist)
+        self.check([a, a], eq=lambda obj: obj[0] is obj[1])
+
+    @unittest.skipIf(numpy is not None, "numpy is installed")
+    def test_numpy_stand_in(self):
+        # Without numpy, the array token is exercised with a minimal stand-in module.
+        class dtype:
+            hasobject = False
+            fields = subdtype = None
+            def __init__(self, str):
+                self.str = str
+        class ndarray:
+            def __init__(self, data, dtype, shape):
+                self.data, self.dtype, self.shape = bytes(data), dtype, shape
+            def tobytes(self):
+                return self.data
+            def reshape(self, shape):
+                return ndarray(self.data, self.dtype, shape)
+        module = types.ModuleType('numpy')
+        module.ndarray = ndarray
+        module.frombuffer = lambda buffer, dtype: ndarray(buffer, dtype, (len(buffer),))
+        with unittest.mock.patch.dict('sys.modules', numpy=module):
+            a = ndarray(range(12), dtype('<i2'), (2, 3))
+            self.check(a, eq=lambda a: (a.data, getattr(a.dtype, 'str', a.dtype), a.shape))
+
+    @unittest.skipIf(numpy is None, "numpy is not installed")
+    def test_numpy_object_array(self):
+        self.check(numpy.array([None, 'a'], dtype=object), eq=numpy.ndarray.tolist)
+
     @unittest.skipIf(numpy is None, "numpy is not installed")
     def test_dispatch_table(self):
         self.check(numpy.sin)
@@ -105,11 +418,55 @@ class Base(unittest.TestCase):
 
 class Nil(Base):
 
-    def check(self, obj, eq=lambda x: x):
-        return stash.hash(obj)
+    def check(self, obj, eq=lambda x: x, **kwargs):
+        return stash.hash(obj, **kwargs)
+
+
+class Store(Base):
+
+    def test_mapping(self):
+        self.assertEqual(len(self.db), 0)
+        h1 = self.db.hash('x' * 300)
+        h2 = self.db.hash(['x' * 300])
+        self.assertEqual(len(self.db), 2)
+        self.assertEqual(set(self.db), {h1, h2})
+        self.assertIn(h1, self.db)
+        self.assertNotIn(stash.hash('y'), self.db)
+        self.assertNotIn(b'invalid', self.db)
+        del self.db[h1]
+        self.assertNotIn(h1, self.db)
+        self.assertEqual(list(self.db), [h2])
+        with self.assertRaises(KeyError):
+            del self.db[h1]
+        with self.assertRaises(ValueError):
+            del self.db[b'invalid']
+
+    def test_gc(self):
+        keep = self.db.hash([('x' * 300,), 'y' * 300])
+        drop = self.db.hash(['z' * 300, 'y' * 300])
+        report = self.db.gc([keep], dry_run=True)
+        self.assertEqual((report.live, report.garbage, report.dry_run), (3, 2, True))
+        self.assertGreater(report.reclaimable, 300)
+        self.db.unhash(drop)
+        report = self.db.gc({keep})
+        self.assertEqual((report.live, report.garbage, report.dry_run), (3, 2, False))
+        self.assertEqual(self.db.unhash(keep), [('x' * 300,), 'y' * 300])
+        with self.assertRaises(KeyError):
+            self.db.unhash(drop)
+        self.assertEqual(self.db.gc([keep]).garbage, 0)
+        self.assertEqual(self.db.hash(['z' * 300, 'y' * 300]), drop)
+        self.assertEqual(self.db.gc([]).garbage, 5)
 
+    def test_gc_missing_root(self):
+        keep = self.db.hash('x' * 300)
+        with self.assertRaises(KeyError):
+            self.db.gc([keep, stash.hash('y' * 300)])
+        with self.assertRaises(ValueError):
+            self.db.gc([b'invalid'])
+        self.assertEqual(self.db.unhash(keep), 'x' * 300)
 
-class PyDB(Base):
+
+class PyDB(Store):
 
     def setUp(self):
         self.d = {}
@@ -119,36 +476,234 @@ class PyDB(Base):
         h = self.db.hash(obj)
         self.assertEqual(len(self.d[h]), n)
 
+    def test_native(self):
+        self.assertEqual(self.d[self.db.hash(...)], b'\x80\x00\x00\x17')
+        self.assertEqual(self.d[self.db.hash(decimal.Decimal('1.10'))], b'\x80\x00\x00\x181.10')
+        self.assertEqual(self.d[self.db.hash(datetime.date(2024, 2, 29))], b'\x80\x00\x00\x1b\x07\xe8\x02\x1d')
+        self.assertEqual(self.d[self.db.hash(datetime.timedelta(-1, 1, 2))],
+            b'\x80\x00\x00\x1e\xff\xff\xff\xff\x00\x00\x01\x00\x00\x02')
+        self.assertEqual(self.d[self.db.hash(pathlib.PurePosixPath('a/b'))], b'\x80\x00\x00\x20\x00a/b')
+
+    def test_earlier_format(self):
+        # an entry that was stored before the blob format byte was introduced
+        h = self.db.hash([1, 2, 3])
+        self.d[h] = self.d[h][3:]
+        with self.assertRaisesRegex(OSError, 'earlier version'):
+            self.db.unhash(h)
+        with self.assertRaisesRegex(OSError, 'earlier version'):
+            self.db.hash([1, 2, 3])
+
     def test_int(self):
-        self.assertLength(-1, 2)
-        self.assertLength(0, 1)
-        self.assertLength(1, 2)
-        self.assertLength(127, 2)
-        self.assertLength(128, 3)
-        self.assertLength(-128, 2)
-        self.assertLength(-129, 3)
+        self.assertLength(-1, 5)
+        self.assertLength(0, 4)
+        self.assertLength(1, 5)
+        self.assertLength(127, 5)
+        self.assertLength(128, 6)
+        self.assertLength(-128, 5)
+        self.assertLength(-129, 6)
+        self.assertLength(2**127 - 1, 20)
+        self.assertLength(2**127, 21)
+        self.assertLength(-2**127, 20)
 
+    def test_foreign_keys(self):
+        h = self.db.hash('x' * 300)
+        self.d['name'] = b'value'
+        self.d[b'short'] = b'value'
+        self.assertEqual(list(self.db), [h])
+        self.assertEqual(len(self.db), 1)
 
-class RAM(Base):
+
+class RAM(Store):
 
     def setUp(self):
         self.db = stash.RAM()
 
+    def test_default_function_mode(self):
+        db = stash.RAM(functions='content')
+        self.assertEqual(db.hash(shifted), self.db.hash(shifted, functions='content'))
+        self.assertEqual(db.hash(shifted, functions='name'), self.db.hash(shifted))
+        with self.assertRaises(ValueError):
+            stash.RAM(functions='invalid')
+
+    def test_invalid_state(self):
+        h = self.db.hash(MyInvalidStateClass())
+        with self.assertRaisesRegex(TypeError, 'state is not a dictionary'):
+            self.db.unhash(h)
 
-class FsDB(Base):
+
+class Cache(Store):
+
+    def setUp(self):
+        self.db = stash.Cache(1 << 30)
+
+    def test_eviction(self):
+        db = stash.Cache(1000)
+        a = db.hash('a' * 300)
+        size = db.nbytes
+        b = db.hash('b' * 300)
+        c = db.hash('c' * 300)
+        self.assertEqual(db.nbytes, 3 * size)
+        # reading a blob gives it a second chance, so that the next blob is evicted instead
+        self.assertEqual(db.unhash(a), 'a' * 300)
+        d = db.hash('d' * 300)
+        self.assertEqual(set(db), {a, c, d})
+        self.assertEqual(db.evictions, 1)
+        self.assertLessEqual(db.nbytes, db.capacity)
+        with self.assertRaises(KeyError):
+            db.unhash(b)
+        self.assertEqual((db.hits, db.misses), (1, 1))
+        del db[c]
+        self.assertEqual(db.nbytes, 2 * size)
+
+    def test_too_large(self):
+        # the cache is lossy, so that the hash of a blob that does not fit is not resolvable
+        db = stash.Cache(100)
+        h = db.hash('x' * 300)
+        self.assertEqual(len(db), 0)
+        self.assertEqual(db.nbytes, 0)
+        self.assertNotIn(h, db)
+
+
+class FsDB(Store):
 
     def setUp(self):
         c = tempfile.TemporaryDirectory()
         self.addCleanup(c.__exit__, None, None, None)
-        self.db = stash.FsDB(c.__enter__())
+        self.root = c.__enter__()
+        self.db = stash.FsDB(self.root)
+
+    def test_write(self):
+        h = self.db.hash('x' * 300)
+        self.assertEqual(os.listdir(os.path.join(self.root, h[:1].hex())), [h[1:].hex()])
+        # a temporary file left behind by an interrupted write is ignored
+        with open(os.path.join(self.root, h[:1].hex(), h[1:].hex() + '.123.tmp'), 'wb') as f:
+            f.write(b'partial')
+        self.assertEqual(set(self.db), {h})
+        self.assertEqual(self.db.hash('x' * 300), h)
+        self.assertEqual(self.db.unhash(h), 'x' * 300)
+
+    def test_layout(self):
+        root = os.path.join(self.root, 'store')
+        db = stash.FsDB(root, depth=2, width=2)
+        self.assertEqual(os.listdir(root), ['layout'])
+        h = db.hash('x' * 300)
+        self.assertTrue(os.path.isfile(os.path.join(root, h[:2].hex(), h[2:4].hex(), h[4:].hex())))
+        db = stash.FsDB(root)
+        self.assertEqual((db.depth, db.width), (2, 2))
+        self.assertEqual(set(db), {h})
+        self.assertEqual(db.unhash(h), 'x' * 300)
+        with self.assertRaises(ValueError):
+            stash.FsDB(root, depth=1)
+        with self.assertRaises(ValueError):
+            stash.FsDB(os.path.join(self.root, 'invalid'), depth=8, width=2)
+        with self.assertRaises(ValueError):
+            stash.FsDB(os.path.join(self.root, 'invalid'), depth=2**40, width=2**40)
+
+    def test_layout_invalid(self):
+        root = os.path.join(self.root, 'store')
+        os.mkdir(root)
+        for layout in ['depth=1 width=0\n', 'depth=4 width=4\n', f'depth={2**64 - 1} width=2\n']:
+            with open(os.path.join(root, 'layout'), 'w') as f:
+                f.write(layout)
+            with self.assertRaises(ValueError):
+                stash.FsDB(root)
+
+    def test_layout_flat(self):
+        db = stash.FsDB(os.path.join(self.root, 'store'), depth=0)
+        h = db.hash('x' * 300)
+        self.assertEqual(set(db), {h})
+        self.assertEqual(db.unhash(h), 'x' * 300)
 
+    def test_layout_legacy(self):
+        self.db.hash('x' * 300)
+        os.remove(os.path.join(self.root, 'layout'))
+        with self.assertRaisesRegex(ValueError, 'earlier version'):
+            stash.FsDB(self.root)
 
-class FileDB(Base):
+    def test_pack(self):
+        obj = ['x' * 300, 'y' * 300]
+        h = self.db.hash(obj)
+        self.assertEqual(self.db.pack(), 3)
+        self.assertEqual(self.db.pack(), 0)
+        self.assertEqual(sorted(os.listdir(self.root)), ['layout', 'pack'])
+        self.assertEqual(self.db.unhash(h), obj)
+        self.assertEqual(len(self.db), 3)
+        self.assertIn(h, self.db)
+        self.assertEqual(self.db.hash(obj), h)
+        db = stash.FsDB(self.root)
+        self.assertEqual(db.unhash(h), obj)
+        # loose objects and packs are combined into a new pack
+        h2 = db.hash('z' * 300)
+        self.assertEqual(self.db.unhash(h2), 'z' * 300)
+        self.assertEqual(db.pack(), 4)
+        self.assertEqual(len([name for name in os.listdir(os.path.join(self.root, 'pack')) if name.endswith('.idx')]), 1)
+        self.assertEqual(self.db.unhash(h2), 'z' * 300)
+        self.assertEqual(set(self.db), set(db))
+
+    def test_pack_stale(self):
+        h = self.db.hash('x' * 300)
+        dir = os.path.join(self.root, h[:1].hex())
+        stale = os.path.join(dir, h[1:].hex() + '.123.tmp')
+        fresh = os.path.join(dir, h[1:].hex() + '.456.tmp')
+        packing = os.path.join(self.root, 'pack', 'tmp-123.pack')
+        os.mkdir(os.path.join(self.root, 'pack'))
+        for path in stale, fresh, packing:
+            with open(path, 'wb') as f:
+                f.write(b'partial')
+        os.utime(stale, (0, 0))
+        # temporary files of blobs are only removed once they are old enough to be abandoned
+        self.assertEqual(self.db.pack(), 1)
+        self.assertEqual(os.listdir(dir), [os.path.basename(fresh)])
+        self.assertNotIn(os.path.basename(packing), os.listdir(os.path.join(self.root, 'pack')))
+        self.assertEqual(self.db.unhash(h), 'x' * 300)
+
+    def test_pack_gc(self):
+        keep = self.db.hash('x' * 300)
+        drop = self.db.hash('y' * 300)
+        self.db.pack()
+        report = self.db.gc([keep])
+        self.assertEqual(report.garbage, 1)
+        self.assertNotIn(drop, self.db)
+        self.assertNotIn(drop, stash.FsDB(self.root))
+        with self.assertRaises(KeyError):
+            self.db.unhash(drop)
+        self.assertEqual(self.db.hash('y' * 300), drop)
+        self.assertEqual(self.db.pack(), 2)
+        self.assertEqual(self.db.unhash(drop), 'y' * 300)
+        del self.db[drop]
+        self.assertEqual(self.db.pack(), 1)
+        self.assertEqual(set(self.db), {keep})
+
+    def test_pack_shared(self):
+        keep = self.db.hash('x' * 300)
+        drop = self.db.hash('y' * 300)
+        self.db.pack()
+        db = stash.FsDB(self.root)
+        self.assertIn(drop, db)
+        # deletions by another instance are seen
+        del self.db[drop]
+        self.assertNotIn(drop, db)
+        with self.assertRaises(KeyError):
+            db.unhash(drop)
+        self.assertEqual(db.hash('y' * 300), drop)
+        self.assertEqual(self.db.unhash(drop), 'y' * 300)
+        # deleting from a pack that was removed by another instance
+        del db[drop]
+        self.assertEqual(db.pack(), 1)
+        with self.assertRaises(KeyError):
+            del self.db[drop]
+        del self.db[keep]
+        self.assertNotIn(keep, db)
+        names = os.listdir(os.path.join(self.root, 'pack'))
+        self.assertEqual({name[:-4] for name in names if name.endswith('.del')}, {name[:-4] for name in names if name.endswith('.idx')})
+
+
+class FileDB(Store):
 
     def setUp(self):
-        c = tempfile.NamedTemporaryFile()
+        c = tempfile.TemporaryDirectory()
         self.addCleanup(c.__exit__, None, None, None)
-        self.dbpath = c.__enter__().name
+        self.dbpath = os.path.join(c.__enter__(), 'db')
         self.db = stash.FileDB(self.dbpath)
 
     def test_reload(self):
@@ -161,5 +716,352 @@ class FileDB(Base):
         self.assertEqual(db.hash(obj1), h1)
         self.assertEqual(db.hash(obj2), h2)
 
+    def test_compact(self):
+        keep = self.db.hash(['x' * 300, 'y' * 300])
+        drop = self.db.hash('z' * 300)
+        self.db.gc([keep])
+        size = os.path.getsize(self.dbpath)
+        self.assertGreater(self.db.compact(), 300)
+        self.assertLess(os.path.getsize(self.dbpath), size - 300)
+        self.assertEqual(self.db.compact(), 0)
+        self.assertEqual(self.db.unhash(keep), ['x' * 300, 'y' * 300])
+        self.assertEqual(self.db.hash('z' * 300), drop)
+        del self.db
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(set(db), {keep, drop, stash.hash('x' * 300), stash.hash('y' * 300)})
+        self.assertEqual(db.unhash(keep), ['x' * 300, 'y' * 300])
+        self.assertEqual(db.unhash(drop), 'z' * 300)
+
+    def test_compact_interrupted(self):
+        h = self.db.hash('x' * 300)
+        with open(self.dbpath + '.compact', 'wb') as f:
+            f.write(b'partial')
+        self.assertEqual(stash.FileDB(self.dbpath).unhash(h), 'x' * 300)
+        self.db.compact()
+        self.assertFalse(os.path.exists(self.dbpath + '.compact'))
+        self.assertEqual(stash.FileDB(self.dbpath).unhash(h), 'x' * 300)
+
+    def test_index(self):
+        h1 = self.db.hash('x' * 300)
+        del self.db
+        with open(self.dbpath + '.index', 'rb') as f:
+            index = f.read()
+        db = stash.FileDB(self.dbpath)
+        h2 = db.hash('y' * 300)
+        del db
+        # a stale index that covers part of the file is extended by scanning the remainder
+        with open(self.dbpath + '.index', 'wb') as f:
+            f.write(index)
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(set(db), {h1, h2})
+        del db
+        with open(self.dbpath + '.index', 'rb') as f:
+            self.assertNotEqual(f.read(), index)
+        # a corrupted index is ignored
+        with open(self.dbpath + '.index', 'r+b') as f:
+            f.seek(50)
+            f.write(b'corrupt')
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.unhash(h1), 'x' * 300)
+        self.assertEqual(db.unhash(h2), 'y' * 300)
+
+    def test_gc_reload(self):
+        keep = self.db.hash('x' * 300)
+        drop = self.db.hash('y' * 300)
+        self.db.gc([keep])
+        del self.db
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.unhash(keep), 'x' * 300)
+        with self.assertRaises(KeyError):
+            db.unhash(drop)
+        self.assertEqual(db.hash('y' * 300), drop)
+        self.assertEqual(db.unhash(drop), 'y' * 300)
+
+    def test_torn_tail(self):
+        keep = self.db.hash('x' * 300)
+        torn = self.db.hash('y' * 300)
+        del self.db
+        size = os.path.getsize(self.dbpath)
+        with open(self.dbpath, 'r+b') as f:
+            f.truncate(size - 100)
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.recovery.truncated, size - 100 - os.path.getsize(self.dbpath))
+        self.assertGreater(db.recovery.truncated, 200)
+        self.assertEqual(db.unhash(keep), 'x' * 300)
+        self.assertNotIn(torn, db)
+        self.assertEqual(db.hash('y' * 300), torn)
+        del db
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.recovery.truncated, 0)
+        self.assertEqual(db.unhash(torn), 'y' * 300)
+
+    def test_checksum(self):
+        keep = self.db.hash('x' * 300)
+        corrupt = self.db.hash('y' * 300)
+        del self.db
+        with open(self.dbpath, 'r+b') as f:
+            f.seek(-100, os.SEEK_END)
+            f.write(b'z')
+        os.remove(self.dbpath + '.index')
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.recovery.scanned, 1)
+        self.assertGreater(db.recovery.truncated, 300)
+        self.assertEqual(set(db), {keep})
+        self.assertEqual(db.unhash(keep), 'x' * 300)
+
+    def test_grow(self):
+        h = self.db.hash('x' * 300)
+        self.assertEqual(self.db.unhash(h), 'x' * 300)
+        hashes = [self.db.hash(str(i) * 1000) for i in range(100)]
+        for i, h in enumerate(hashes):
+            self.assertEqual(self.db.unhash(h), str(i) * 1000)
+        self.assertEqual(self.db.unhash(self.db.hash(hashes)), hashes)
+
+    def test_shared(self):
+        other = stash.FileDB(self.dbpath)
+        h1 = self.db.hash('x' * 300)
+        self.assertIn(h1, other)
+        self.assertEqual(other.unhash(h1), 'x' * 300)
+        h2 = other.hash('y' * 300)
+        self.assertEqual(self.db.unhash(h2), 'y' * 300)
+        self.assertEqual(self.db.hash('y' * 300), h2)
+        del self.db[h1]
+        self.assertEqual(set(other), {h2})
+        # appends after a compaction by another process go to the new file
+        self.db.compact()
+        h3 = other.hash('z' * 300)
+        self.assertEqual(self.db.unhash(h3), 'z' * 300)
+        del self.db, other
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.recovery.truncated, 0)
+        self.assertEqual(set(db), {h2, h3})
+
+    def test_shared_delete(self):
+        other = stash.FileDB(self.dbpath)
+        h = self.db.hash('x' * 300)
+        self.assertIn(h, other)
+        del self.db[h]
+        self.assertEqual(other.hash('x' * 300), h)
+        self.assertIn(h, stash.FileDB(self.dbpath))
+        del self.db[h]
+        self.db.compact()
+        self.assertEqual(other.hash('x' * 300), h)
+        self.assertIn(h, stash.FileDB(self.dbpath))
+
+    @unittest.skipIf(fcntl is None, "fcntl is not available")
+    def test_close_locked(self):
+        h = self.db.hash('x' * 300)
+        with open(self.dbpath, 'rb') as f:
+            fcntl.flock(f, fcntl.LOCK_EX)
+            del self.db
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.recovery.scanned, 1)
+        self.assertIn(h, db)
+
+    @unittest.skipIf('fork' not in multiprocessing.get_all_start_methods(), "fork is not available")
+    def test_processes(self):
+        with multiprocessing.get_context('fork').Pool(4) as pool:
+            hashes = pool.starmap(fill_filedb, [(self.dbpath, worker) for worker in range(4)])
+        db = stash.FileDB(self.dbpath)
+        self.assertEqual(db.recovery.truncated, 0)
+        for worker, worker_hashes in enumerate(hashes):
+            for i, h in enumerate(worker_hashes):
+                self.assertEqual(db.unhash(h), [worker, i, 'x' * i])
+
+    def test_not_a_database(self):
+        with open(self.dbpath, 'wb') as f:
+            f.write(b'not a database')
+        with self.assertRaises(OSError):
+            stash.FileDB(self.dbpath)
+        with open(self.dbpath, 'rb') as f:
+            self.assertEqual(f.read(), b'not a database')
+
+
+class SqliteDB(Store):
+
+    def setUp(self):
+        c = tempfile.TemporaryDirectory()
+        self.addCleanup(c.__exit__, None, None, None)
+        self.dbpath = os.path.join(c.__enter__(), 'db.sqlite')
+        self.db = stash.SqliteDB(self.dbpath)
+
+    def test_reload(self):
+        obj = ['x' * 300, 'y' * 300]
+        h = self.db.hash(obj)
+        del self.db
+        db = stash.SqliteDB(self.dbpath)
+        self.assertEqual(db.unhash(h), obj)
+        self.assertEqual(len(db), 3)
+
+    def test_transaction(self):
+        class Local:
+            pass
+        with self.assertRaises(stash.UnresolvableError):
+            self.db.hash(['x' * 300, Local])
+        self.assertEqual(len(self.db), 0)
+
+    def test_inspect(self):
+        h = self.db.hash('x' * 300)
+        with sqlite3.connect(self.dbpath) as conn:
+            self.assertEqual(conn.execute('SELECT hash FROM blobs').fetchall(), [(h,)])
+
+
+class Tiered(Store):
+
+    def setUp(self):
+        self.upper = stash.RAM()
+        self.lower = stash.RAM()
+        self.db = stash.Tiered([self.upper, self.lower])
+
+    def test_read_through(self):
+        obj = ['x' * 300, 'y' * 300]
+        h = self.lower.hash(obj)
+        self.assertEqual(self.db.unhash(h), obj)
+        self.assertEqual(set(self.upper), set(self.lower))
+
+    def test_write_through(self):
+        h = self.db.hash(['x' * 300, 'y' * 300])
+        self.assertEqual(len(self.upper), 3)
+        self.assertEqual(set(self.upper), set(self.lower))
+        del self.db[h]
+        self.assertNotIn(h, self.upper)
+        self.assertNotIn(h, self.lower)
+
+    def test_write_back(self):
+        db = stash.Tiered([self.upper, self.lower], policy='write-back')
+        obj = ['x' * 300, 'y' * 300]
+        h = db.hash(obj)
+        self.assertEqual(len(self.upper), 3)
+        self.assertEqual(len(self.lower), 0)
+        self.assertEqual(db.flush(), 3)
+        self.assertEqual(self.lower.unhash(h), obj)
+        self.assertEqual(db.flush(), 0)
+        h = db.hash('z' * 300)
+        del db
+        self.assertEqual(self.lower.unhash(h), 'z' * 300)
+        with self.assertRaises(ValueError):
+            stash.Tiered([], policy='invalid')
+        # a top tier that may evict its blobs would lose those that were not written back
+        for top in [stash.Cache(1 << 20), stash.Tiered([stash.Cache(1 << 20)]),
+                    stash.Overlay(stash.Cache(1 << 20), stash.RAM())]:
+            with self.assertRaises(ValueError):
+                stash.Tiered([top, self.lower], policy='write-back')
+        top = stash.Tiered([stash.Cache(1 << 20), stash.RAM()])
+        stash.Tiered([top, self.lower], policy='write-back')
+
+    def test_write_back_error(self):
+        class Lower(dict):
+            def __setitem__(self, key, value):
+                raise RuntimeError
+        db = stash.Tiered([self.upper, Lower()], policy='write-back')
+        db.hash('x' * 300)
+        with self.assertRaises(RuntimeError):
+            db.flush()
+        with unittest.mock.patch('sys.unraisablehook') as hook:
+            del db
+        hook.assert_called_once()
+        self.assertIs(hook.call_args[0][0].exc_type, RuntimeError)
+
+    def test_filedb(self):
+        with tempfile.TemporaryDirectory() as dir:
+            path = os.path.join(dir, 'db')
+            obj = ['x' * 300, 'y' * 300]
+            h = stash.FileDB(path).hash(obj)
+            cache = stash.Cache(1 << 20)
+            db = stash.Tiered([cache, stash.FileDB(path)])
+            self.assertEqual(db.unhash(h), obj)
+            self.assertEqual(db.unhash(h), obj)
+            self.assertEqual((cache.hits, cache.misses), (3, 3))
+
+    def test_mapping(self):
+        upper = {}
+        obj = ['x' * 300, 'y' * 300]
+        h = self.lower.hash(obj)
+        db = stash.Tiered([upper, self.lower])
+        self.assertEqual(db.unhash(h), obj)
+        self.assertEqual(set(upper), set(self.lower))
+
+
+class Overlay(Store):
+
+    def setUp(self):
+        self.top = {}
+        self.base = {}
+        self.db = stash.Overlay(self.top, self.base)
+
+    def test_fall_through(self):
+        obj = ['x' * 300, 'y' * 300]
+        h = stash.PyDB(self.base).hash(obj)
+        self.assertEqual(self.db.unhash(h), obj)
+        self.assertIn(h, self.db)
+        self.assertEqual(self.db.hash(obj), h)
+        self.assertEqual(self.top, {})
+        h2 = self.db.hash([obj, 'z' * 300])
+        self.assertEqual(len(self.top), 2)
+        self.assertEqual(len(self.db), 5)
+        with self.assertRaises(PermissionError):
+            del self.db[h]
+        del self.db[h2]
+        self.assertEqual(self.db.gc([]).garbage, 1)
+        self.assertEqual(self.top, {})
+        self.assertEqual(len(self.base), 3)
+
+    def test_collision(self):
+        h = stash.PyDB(self.base).hash('x' * 300)
+        self.base[h] = self.base.pop(stash.PyDB(self.base).hash('y' * 300))
+        with self.assertRaises(LookupError):
+            self.db.hash('x' * 300)
+        self.assertEqual(self.top, {})
+
+
+class Sharded(Store):
+
+    def setUp(self):
+        c = tempfile.TemporaryDirectory()
+        self.addCleanup(c.__exit__, None, None, None)
+        self.dir = c.__enter__()
+        self.manifest = os.path.join(self.dir, 'manifest')
+        self.shards = [os.path.join(self.dir, f'shard{i}') for i in range(3)]
+        self.db = stash.Sharded(self.manifest, self.shards)
+
+    def test_routing(self):
+        obj = ['x' * 300 + str(i) for i in range(30)]
+        h = self.db.hash(obj)
+        del self.db
+        shards = [stash.FileDB(shard) for shard in self.shards]
+        self.assertEqual(sum(map(len, shards)), 31)
+        for i, shard in enumerate(shards):
+            self.assertGreater(len(shard), 0)
+            for k in shard:
+                self.assertEqual(int.from_bytes(k[:8], 'big') % 3, i)
+        del shards
+        self.assertEqual(stash.Sharded(self.manifest).unhash(h), obj)
+
+    def test_manifest(self):
+        with open(self.manifest) as f:
+            lines = f.read().splitlines()
+        self.assertEqual(lines[:3], ['stash-sharded 1', 'layout prefix64-mod', 'backend filedb'])
+        self.assertEqual(lines[3:], [f'shard {shard}' for shard in self.shards])
+        self.assertEqual([name for name in os.listdir(self.dir) if name.startswith('manifest')], ['manifest'])
+        self.assertEqual(len(stash.Sharded(self.manifest, self.shards, backend='filedb')), 0)
+        with self.assertRaises(ValueError):
+            stash.Sharded(self.manifest, self.shards[:2])
+        with self.assertRaises(ValueError):
+            stash.Sharded(self.manifest, backend='fsdb')
+        with self.assertRaises(ValueError):
+            stash.Sharded(os.path.join(self.dir, 'other'))
+        with self.assertRaises(ValueError):
+            stash.Sharded(os.path.join(self.dir, 'other'), ['a'], backend='zip')
+
+    def test_relative(self):
+        manifest = os.path.join(self.dir, 'relative', 'manifest')
+        os.mkdir(os.path.dirname(manifest))
+        db = stash.Sharded(manifest, ['a', 'b'], backend='sqlite')
+        h = db.hash('x' * 300)
+        del db
+        self.assertTrue(os.path.isfile(os.path.join(self.dir, 'relative', 'a')))
+        self.assertTrue(os.path.isfile(os.path.join(self.dir, 'relative', 'b')))
+        self.assertEqual(stash.Sharded(manifest).unhash(h), 'x' * 300)
+
 
-del Base
+del Base, Store